@@ -1,31 +1,71 @@
-use crate::middleware::tracing::TraceInfo;
-use crate::AppContext;
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use crate::metrics::{MetricKey, MetricsRegistry};
+use crate::middleware::tracing::{ErrorType, TraceInfo};
+use crate::{AppContext, RandomConfig};
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use futures_util::future::BoxFuture;
+use opentelemetry::metrics::Counter;
+use opentelemetry::trace::TraceId;
 use opentelemetry::KeyValue;
 use opentelemetry_semantic_conventions::attribute::HTTP_REQUEST_METHOD;
-use rand::Rng;
+use serde::Deserialize;
 use serde_json::json;
+use std::fmt::Debug;
 use std::time::Duration;
 use tracing::log::info;
-use tracing::{instrument, Span};
+use tracing::{instrument, Instrument, Span};
 
+// record_trace doesn't enter the request span while handlers run, so each
+// handler is instrumented with it as the explicit parent. The span is named
+// after the handler and its module is exported as `code.namespace`.
 #[get("/")]
+#[instrument(skip_all, parent = trace_info.app_root_span.clone())]
 pub async fn hello(trace_info: web::ReqData<TraceInfo>) -> impl Responder {
-    foo(trace_info.into_inner()).await;
+    foo().await;
     info!("hello world!");
     HttpResponse::Ok().body("Hello world!")
 }
 
+/// Picks and waits out the `/random` delay, so tests can swap in a
+/// deterministic source that doesn't sleep.
+pub trait DelaySource: Debug + Send + Sync {
+    fn sample(&self, config: &RandomConfig) -> u64;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Samples with `rand::thread_rng` and sleeps on the tokio timer.
+#[derive(Debug, Default)]
+pub struct ThreadRngDelay;
+
+impl DelaySource for ThreadRngDelay {
+    fn sample(&self, config: &RandomConfig) -> u64 {
+        config.sample(&mut rand::thread_rng())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
 #[get("/random")]
-pub async fn random(trace_info: web::ReqData<TraceInfo>) -> impl Responder {
-    foo(trace_info.into_inner()).await;
-    let duration = rand::thread_rng().gen_range(1..5);
-    tokio::time::sleep(Duration::from_secs(duration)).await;
-    info!("took {} seconds", duration);
-    HttpResponse::Ok().json(json!({"duration": duration}))
+#[instrument(skip_all, parent = trace_info.app_root_span.clone())]
+pub async fn random(
+    context: web::Data<AppContext>,
+    trace_info: web::ReqData<TraceInfo>,
+) -> impl Responder {
+    foo().await;
+    let config = context.random_config();
+    let delay = context.delay_source();
+    let duration = delay.sample(config);
+    delay.sleep(config.duration(duration)).await;
+    info!("took {} {}", duration, config.unit.as_str());
+    HttpResponse::Ok().json(json!({"duration": duration, "unit": config.unit.as_str()}))
 }
 
 #[post("/echo")]
+#[instrument(skip_all, parent = trace_info.app_root_span.clone())]
 pub async fn echo(
     req: HttpRequest,
     req_body: String,
@@ -35,28 +75,398 @@ pub async fn echo(
         tracing::Level::INFO,
         { HTTP_REQUEST_METHOD } = req.method().as_str(),
     );
-    foo(trace_info.into_inner()).await;
+    foo().await;
     HttpResponse::Ok().body(req_body)
 }
 
+#[get("/error/{code}")]
+#[instrument(skip_all, parent = trace_info.app_root_span.clone())]
+pub async fn error(code: web::Path<u16>, trace_info: web::ReqData<TraceInfo>) -> impl Responder {
+    let code = code.into_inner();
+    let status = match StatusCode::from_u16(code) {
+        Ok(status) if status.is_client_error() || status.is_server_error() => status,
+        _ => {
+            return HttpResponse::BadRequest()
+                .body(format!("status code must be between 400 and 599: {}", code))
+        }
+    };
+    foo().await;
+    if status.is_server_error() {
+        tracing::event!(
+            tracing::Level::ERROR,
+            "simulated downstream error: {}",
+            status
+        );
+    }
+    HttpResponse::build(status).finish()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    pub name: String,
+}
+
+/// Processes `job` in a spawned task after responding. `tokio::spawn` doesn't
+/// carry the current span over, so the task is instrumented with a child of
+/// the handler span; otherwise it would start a new trace.
+#[post("/enqueue")]
+#[instrument(skip_all, parent = trace_info.app_root_span.clone())]
+pub async fn enqueue(job: web::Json<Job>, trace_info: web::ReqData<TraceInfo>) -> impl Responder {
+    let job = job.into_inner();
+    let span = tracing::info_span!(
+        parent: Span::current(),
+        "process job",
+        job.name = job.name.as_str()
+    );
+    tokio::spawn(
+        async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            info!("processed job {}", job.name);
+        }
+        .instrument(span),
+    );
+    HttpResponse::Accepted().finish()
+}
+
+pub const OPS_COUNT: MetricKey<Counter<f64>> = MetricKey::new("ops_count");
+
+fn ops_count_attributes() -> [KeyValue; 1] {
+    [KeyValue::new("my-key", "my-value")]
+}
+
+/// Registers the instruments used by the handlers in this module. Counters
+/// start at zero, so they are exported before the handlers first run.
+pub fn register_metrics(registry: &mut MetricsRegistry) {
+    registry.register(&OPS_COUNT, |meter, name| {
+        let counter = meter.f64_counter(name).init();
+        counter.add(0.0, &ops_count_attributes());
+        counter
+    });
+}
+
+/// Percentiles of recent request durations, in milliseconds, from the
+/// in-memory window filled by `HttpMetrics`.
+#[get("/admin/latency")]
+#[instrument(skip_all, parent = trace_info.app_root_span.clone())]
+pub async fn latency(
+    context: web::Data<AppContext>,
+    trace_info: web::ReqData<TraceInfo>,
+) -> impl Responder {
+    let window = context.latency_window();
+    let percentiles = window
+        .percentiles([0.5, 0.9, 0.99])
+        .map(|percentiles| percentiles.map(|duration| duration.as_secs_f64() * 1_000.0));
+    let [p50, p90, p99] = match percentiles {
+        Some([p50, p90, p99]) => [Some(p50), Some(p90), Some(p99)],
+        None => [None; 3],
+    };
+    HttpResponse::Ok().json(json!({
+        "count": window.len(),
+        "p50_ms": p50,
+        "p90_ms": p90,
+        "p99_ms": p99,
+    }))
+}
+
 #[post("/metrics")]
-pub async fn metrics(context: web::Data<AppContext>) -> impl Responder {
-    let counter = context.meter.f64_counter("ops_count").init();
-    counter.add(1.0, &[KeyValue::new("my-key", "my-value")]);
-    HttpResponse::Ok()
+#[instrument(skip_all, parent = trace_info.app_root_span.clone())]
+pub async fn metrics(
+    context: web::Data<AppContext>,
+    trace_info: web::ReqData<TraceInfo>,
+) -> impl Responder {
+    if let Some(counter) = context.metrics().get(&OPS_COUNT) {
+        counter.add(1.0, &ops_count_attributes());
+    }
+    HttpResponse::Ok().finish()
 }
 
 pub fn route(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("")
+            .app_data(json_config())
             .service(hello)
             .service(echo)
+            .service(enqueue)
+            .service(error)
+            .service(latency)
             .service(metrics)
             .service(random),
     );
 }
 
-#[instrument(parent = _trace_info.app_root_span.clone())]
-async fn foo(_trace_info: TraceInfo) {
+/// Rejects malformed JSON bodies with `{"error", "trace_id"}` instead of
+/// actix's plain text, tagging the span's `error.type` with the failure kind
+/// so it is recorded even for a 4xx. `trace_id` is null for untraced requests.
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let error_type = match &err {
+        JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+            "json.overflow"
+        }
+        JsonPayloadError::ContentType => "json.content_type",
+        JsonPayloadError::Deserialize(_) => "json.deserialize",
+        _ => "json.payload",
+    };
+    let trace_id = req
+        .extensions()
+        .get::<TraceInfo>()
+        .map(|trace_info| trace_info.trace_id)
+        .filter(|trace_id| *trace_id != TraceId::INVALID)
+        .map(|trace_id| trace_id.to_string());
+    let mut response = HttpResponse::build(err.status_code())
+        .json(json!({"error": err.to_string(), "trace_id": trace_id}));
+    response.extensions_mut().insert(ErrorType(error_type));
+    InternalError::from_response(err, response).into()
+}
+
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(json_error_handler)
+}
+
+#[instrument]
+async fn foo() {
     tracing::info_span!("this is inside the foo func");
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{route, DelaySource};
+    use crate::metrics::LatencyWindow;
+    use crate::middleware::metrics::HttpMetrics;
+    use crate::middleware::tracing::record_trace;
+    use crate::{AppContext, DurationUnit, MetricsConfig, RandomConfig};
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App};
+    use futures_util::future::BoxFuture;
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    /// Samples from a seeded RNG and records the requested sleeps instead of
+    /// waiting.
+    #[derive(Debug)]
+    struct SeededDelay {
+        rng: Mutex<StdRng>,
+        slept: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl DelaySource for SeededDelay {
+        fn sample(&self, config: &RandomConfig) -> u64 {
+            config.sample(&mut *self.rng.lock().unwrap())
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.slept.lock().unwrap().push(duration);
+            Box::pin(async {})
+        }
+    }
+
+    #[tokio::test]
+    async fn test_random_with_injected_delay() {
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let delay = SeededDelay {
+            rng: Mutex::new(StdRng::seed_from_u64(7)),
+            slept: slept.clone(),
+        };
+        let expected = RandomConfig::default().sample(&mut StdRng::seed_from_u64(7));
+        let context =
+            AppContext::new(SdkMeterProvider::default()).with_delay_source(Arc::new(delay));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+
+        let start = Instant::now();
+        let req = test::TestRequest::get().uri("/random").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(body["duration"], expected);
+        assert_eq!(body["unit"], "s");
+        assert_eq!(*slept.lock().unwrap(), [Duration::from_secs(expected)]);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_propagates_context() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .set_default();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::post()
+            .uri("/enqueue")
+            .set_json(serde_json::json!({"name": "report"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 202);
+        drop(resp);
+
+        // The job span keeps the handler and request spans open until it ends.
+        let start = Instant::now();
+        let spans = loop {
+            let spans = exporter.get_finished_spans().unwrap();
+            if spans.iter().any(|span| span.name == "POST /enqueue") {
+                break spans;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "job never ran");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        let find = |name: &str| spans.iter().find(|span| span.name == name).unwrap();
+        let request_span = find("POST /enqueue");
+        let handler_span = find("enqueue");
+        let job_span = find("process job");
+        assert_eq!(
+            job_span.span_context.trace_id(),
+            request_span.span_context.trace_id()
+        );
+        assert_eq!(job_span.parent_span_id, handler_span.span_context.span_id());
+        assert_eq!(
+            handler_span.parent_span_id,
+            request_span.span_context.span_id()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handler_span() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .set_default();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = spans.iter().find(|span| span.name == "GET /").unwrap();
+        let handler_span = spans.iter().find(|span| span.name == "hello").unwrap();
+        assert_eq!(
+            handler_span.parent_span_id,
+            request_span.span_context.span_id()
+        );
+        let foo_span = spans.iter().find(|span| span.name == "foo").unwrap();
+        assert_eq!(foo_span.parent_span_id, handler_span.span_context.span_id());
+        let namespace = handler_span
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "code.namespace")
+            .map(|kv| kv.value.as_str().into_owned());
+        assert_eq!(namespace.as_deref(), Some("actix_otel_example::api"));
+    }
+
+    #[tokio::test]
+    async fn test_json_error_recorded() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .set_default();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::post()
+            .uri("/enqueue")
+            .set_json(serde_json::json!({"title": "report"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("missing field `name`"));
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = spans
+            .iter()
+            .find(|span| span.name == "POST /enqueue")
+            .unwrap();
+        assert_eq!(
+            body["trace_id"],
+            request_span.span_context.trace_id().to_string()
+        );
+        let attribute = |key: &str| {
+            request_span
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.as_str().into_owned())
+        };
+        assert_eq!(attribute("error.type").as_deref(), Some("json.deserialize"));
+        assert!(attribute("exception.message")
+            .unwrap()
+            .contains("missing field `name`"));
+        assert!(matches!(
+            request_span.status,
+            opentelemetry::trace::Status::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_latency_percentiles() {
+        let meter_provider = SdkMeterProvider::default();
+        let latency_window = Arc::new(LatencyWindow::default());
+        let context = AppContext::new(meter_provider.clone())
+            .with_latency_window(latency_window.clone())
+            .with_random_config(RandomConfig {
+                unit: DurationUnit::Milliseconds,
+                ..RandomConfig::default()
+            });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(from_fn(record_trace))
+                .wrap(
+                    HttpMetrics::new(
+                        Arc::new(meter_provider.meter("test")),
+                        MetricsConfig::default(),
+                    )
+                    .with_latency_window(latency_window),
+                )
+                .configure(route),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/admin/latency").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["count"], 0);
+        assert!(body["p50_ms"].is_null());
+
+        for _ in 0..10 {
+            let req = test::TestRequest::get().uri("/random").to_request();
+            test::call_service(&app, req).await;
+        }
+        let req = test::TestRequest::get().uri("/admin/latency").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        // The first /admin/latency request is recorded too.
+        assert_eq!(body["count"], 11);
+        let p50 = body["p50_ms"].as_f64().unwrap();
+        let p90 = body["p90_ms"].as_f64().unwrap();
+        let p99 = body["p99_ms"].as_f64().unwrap();
+        assert!(p50 > 0.0 && p50 <= p90 && p90 <= p99);
+        // /random sleeps 1 to 4ms here.
+        assert!((1.0..1_000.0).contains(&p99));
+    }
+}
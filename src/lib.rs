@@ -1,28 +1,1349 @@
+use crate::api::{register_metrics, DelaySource, ThreadRngDelay};
+use crate::metrics::{LatencyWindow, MetricsRegistry, TenantMeters, DEFAULT_MAX_TENANTS};
+use opentelemetry::global::BoxedTracer;
 use opentelemetry::metrics::Meter;
+use opentelemetry::metrics::MeterProvider as _;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use rand::Rng;
 use serde::Deserialize;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 pub mod api;
+pub mod logfmt;
+pub mod metrics;
 pub mod middleware;
 pub mod telemetry;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
+pub const METER_NAME: &str = "rust-telemetry-example";
+
+/// Shared state for handlers. Meters come from the injected provider rather
+/// than the global one, so tests can pass their own.
 #[derive(Debug)]
 pub struct AppContext {
-    meter: Arc<Meter>,
+    meter_provider: SdkMeterProvider,
+    tracer: BoxedTracer,
+    metrics: MetricsRegistry,
+    random_config: RandomConfig,
+    delay_source: Arc<dyn DelaySource>,
+    latency_window: Arc<LatencyWindow>,
+    tenant_meters: Arc<TenantMeters>,
+    /// Kept so observable instruments live as long as the context.
+    observables: Vec<Box<dyn Any + Send + Sync>>,
+    dependencies: Vec<(&'static str, HealthCheck)>,
+}
+
+/// Reports whether a downstream dependency is currently healthy.
+struct HealthCheck(Box<dyn Fn() -> bool + Send + Sync>);
+
+impl fmt::Debug for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HealthCheck")
+    }
 }
 
 impl AppContext {
-    pub fn new(meter: Arc<Meter>) -> Self {
-        Self { meter }
+    pub fn new(meter_provider: SdkMeterProvider) -> Self {
+        let mut metrics = MetricsRegistry::new(Arc::new(meter_provider.meter(METER_NAME)));
+        register_metrics(&mut metrics);
+        let tenant_meters = Arc::new(TenantMeters::new(meter_provider.clone()));
+        Self {
+            meter_provider,
+            tracer: opentelemetry::global::tracer(METER_NAME),
+            metrics,
+            random_config: RandomConfig::default(),
+            delay_source: Arc::new(ThreadRngDelay),
+            latency_window: Arc::new(LatencyWindow::default()),
+            tenant_meters,
+            observables: Vec::new(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    /// Registers a downstream dependency whose health `check` reports, e.g.
+    /// from a flag a background pinger keeps up to date. It is called on every
+    /// [AppContext::record_dependency_health], so it should be cheap.
+    pub fn register_dependency<F>(&mut self, name: &'static str, check: F) -> &mut Self
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        self.dependencies.push((name, HealthCheck(Box::new(check))));
+        self
+    }
+
+    /// Records `{name}.healthy` for every registered dependency on the
+    /// current span.
+    pub fn record_dependency_health(&self) {
+        let span = tracing::Span::current();
+        for (name, HealthCheck(check)) in &self.dependencies {
+            span.set_attribute(format!("{name}.healthy"), check());
+        }
+    }
+
+    /// Registers a gauge reporting `callback()` on every collection cycle,
+    /// e.g. a queue depth or pool size owned elsewhere.
+    pub fn register_observable_gauge<F>(&mut self, name: &'static str, callback: F) -> &mut Self
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        let gauge = self
+            .metrics
+            .meter()
+            .f64_observable_gauge(name)
+            .with_callback(move |observer| observer.observe(callback(), &[]))
+            .init();
+        self.observables.push(Box::new(gauge));
+        self
+    }
+
+    /// Registers a monotonic counter whose running total is `callback()`,
+    /// read on every collection cycle.
+    pub fn register_observable_counter<F>(&mut self, name: &'static str, callback: F) -> &mut Self
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        let counter = self
+            .metrics
+            .meter()
+            .u64_observable_counter(name)
+            .with_callback(move |observer| observer.observe(callback(), &[]))
+            .init();
+        self.observables.push(Box::new(counter));
+        self
+    }
+
+    /// Shares `latency_window` with [middleware::metrics::HttpMetrics], which
+    /// fills it.
+    pub fn with_latency_window(mut self, latency_window: Arc<LatencyWindow>) -> Self {
+        self.latency_window = latency_window;
+        self
+    }
+
+    /// Shares `tenant_meters` with [middleware::metrics::HttpMetrics] and other
+    /// workers, so each tenant scope is only created once.
+    pub fn with_tenant_meters(mut self, tenant_meters: Arc<TenantMeters>) -> Self {
+        self.tenant_meters = tenant_meters;
+        self
+    }
+
+    /// Replaces the tracer taken from the global provider when the context
+    /// was created.
+    pub fn with_tracer(mut self, tracer: BoxedTracer) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    pub fn with_delay_source(mut self, delay_source: Arc<dyn DelaySource>) -> Self {
+        self.delay_source = delay_source;
+        self
+    }
+
+    pub fn with_random_config(mut self, random_config: RandomConfig) -> Self {
+        self.random_config = random_config;
+        self
+    }
+
+    pub fn meter_provider(&self) -> &SdkMeterProvider {
+        &self.meter_provider
+    }
+
+    /// For spans that need the OpenTelemetry API directly, e.g. explicit
+    /// start times or links. Parent them on the request span with
+    /// `tracer.start_with_context(name, &trace_info.app_root_span.context())`.
+    pub fn tracer(&self) -> &BoxedTracer {
+        &self.tracer
+    }
+
+    /// A meter whose metrics are exported in a scope of `tenant`'s own, see
+    /// [TenantMeters] for the cost of each scope. Past the tenant limit this
+    /// is the default meter.
+    pub fn tenant_meter(&self, tenant: &str) -> Arc<Meter> {
+        self.tenant_meters
+            .meter(tenant)
+            .unwrap_or_else(|| Arc::new(self.metrics.meter().clone()))
+    }
+
+    pub fn tenant_meters(&self) -> &Arc<TenantMeters> {
+        &self.tenant_meters
+    }
+
+    pub fn metrics(&self) -> &MetricsRegistry {
+        &self.metrics
+    }
+
+    pub fn random_config(&self) -> &RandomConfig {
+        &self.random_config
+    }
+
+    pub fn delay_source(&self) -> &dyn DelaySource {
+        self.delay_source.as_ref()
+    }
+
+    pub fn latency_window(&self) -> &LatencyWindow {
+        &self.latency_window
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     pub otel_config: OtelConfig,
+    #[serde(default)]
+    pub server_config: ServerConfig,
+    #[serde(default)]
+    pub random_config: RandomConfig,
+}
+
+impl AppConfig {
+    /// Reads `path` and, when `app_env` is set, overlays `app.{app_env}.toml`
+    /// from the same directory. Tables are merged key by key and any other
+    /// value in the overlay replaces the base one. A missing overlay is
+    /// ignored, so environments without overrides need no file.
+    pub fn load(path: impl AsRef<Path>, app_env: Option<&str>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let base = read_file(path)?;
+        let Some(app_env) = app_env else {
+            // Deserializing straight from the text keeps line and column
+            // information for type errors too.
+            return toml::from_str(&base).map_err(|source| ConfigError::Parse {
+                origin: path.display().to_string(),
+                source,
+            });
+        };
+
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("app");
+        let overlay_path = path.with_file_name(format!("{stem}.{app_env}.toml"));
+        let overlay = match read_file(&overlay_path) {
+            Ok(overlay) => overlay,
+            Err(ConfigError::Read { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                return Self::load(path, None);
+            }
+            Err(err) => return Err(err),
+        };
+        let mut config = parse_table(path, &base)?;
+        merge_tables(&mut config, parse_table(&overlay_path, &overlay)?);
+        // Merged values have no position, but the error names the key.
+        config.try_into().map_err(|source| ConfigError::Parse {
+            origin: format!("{} with {}", path.display(), overlay_path.display()),
+            source,
+        })
+    }
+
+    /// Loads the config like [AppConfig::load], validates it and builds the
+    /// exporters without installing them. Returns every problem found, so an
+    /// empty list means the server would start with this config.
+    pub fn check(path: impl AsRef<Path>, app_env: Option<&str>) -> Vec<String> {
+        match Self::load(path, app_env) {
+            Ok(config) => {
+                let mut problems = config.validate();
+                problems.extend(telemetry::check_exporters(&config.otel_config));
+                problems
+            }
+            Err(err) => vec![err.to_string()],
+        }
+    }
+
+    /// Values that parse but would be rejected or silently misbehave at runtime.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let otel_config = &self.otel_config;
+        let ratio = otel_config.tracing_config.sampler().ratio();
+        if !(0.0..=1.0).contains(&ratio) {
+            problems.push(format!(
+                "tracing_config: sampling ratio {ratio} is not between 0 and 1"
+            ));
+        }
+        for (route, rate) in &otel_config.tracing_config.route_sample_rates {
+            if !(0.0..=1.0).contains(rate) {
+                problems.push(format!(
+                    "route_sample_rates: rate {rate} for {route:?} is not between 0 and 1"
+                ));
+            }
+        }
+        if let Some(tail_sampling) = &otel_config.tail_sampling {
+            if !(0.0..=1.0).contains(&tail_sampling.sample_ratio) {
+                problems.push(format!(
+                    "tail_sampling: sample_ratio {} is not between 0 and 1",
+                    tail_sampling.sample_ratio
+                ));
+            }
+        }
+        if otel_config
+            .circuit_breaker
+            .as_ref()
+            .is_some_and(|circuit_breaker| circuit_breaker.failure_threshold == 0)
+        {
+            problems.push("circuit_breaker: failure_threshold must be at least 1".to_string());
+        }
+        if self.random_config.min > self.random_config.max {
+            problems.push(format!(
+                "random_config: min {} is greater than max {}",
+                self.random_config.min, self.random_config.max
+            ));
+        }
+        problems
+    }
+}
+
+/// Why [AppConfig::load] failed. Parse errors name the file and, where known,
+/// the line and column.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        origin: String,
+        source: toml::de::Error,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "failed to read {}: {source}", path.display())
+            }
+            ConfigError::Parse { origin, source } => {
+                write!(f, "failed to parse {origin}: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn parse_table(path: &Path, value: &str) -> Result<toml::Table, ConfigError> {
+    toml::from_str(value).map_err(|source| ConfigError::Parse {
+        origin: path.display().to_string(),
+        source,
+    })
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Sleep range of the `/random` endpoint: `min` inclusive, `max` exclusive, in
+/// `unit`. Defaults to 1 to 5 seconds.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RandomConfig {
+    #[serde(default = "RandomConfig::default_min")]
+    pub min: u64,
+    #[serde(default = "RandomConfig::default_max")]
+    pub max: u64,
+    #[serde(default)]
+    pub unit: DurationUnit,
+}
+
+impl RandomConfig {
+    fn default_min() -> u64 {
+        1
+    }
+
+    fn default_max() -> u64 {
+        5
+    }
+
+    /// Picks a sleep length from the range; an empty range always yields `min`.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        if self.min < self.max {
+            rng.gen_range(self.min..self.max)
+        } else {
+            self.min
+        }
+    }
+
+    pub fn duration(&self, value: u64) -> Duration {
+        match self.unit {
+            DurationUnit::Seconds => Duration::from_secs(value),
+            DurationUnit::Milliseconds => Duration::from_millis(value),
+        }
+    }
+}
+
+impl Default for RandomConfig {
+    fn default() -> Self {
+        Self {
+            min: Self::default_min(),
+            max: Self::default_max(),
+            unit: DurationUnit::default(),
+        }
+    }
+}
+
+/// Tuning knobs for `HttpServer`; unset values keep actix's defaults.
+///
+/// The effective worker count is logged by actix on startup
+/// (`starting N workers`).
+#[derive(Debug, Default, Deserialize)]
+pub struct ServerConfig {
+    pub workers: Option<usize>,
+    pub backlog: Option<u32>,
+    /// Requests with a larger body, in bytes, are rejected with 413.
+    pub max_body_size: Option<u64>,
+    /// Requests still running after this many milliseconds get 504.
+    pub request_timeout_ms: Option<u64>,
+    /// Per-route overrides of `request_timeout_ms`, keyed by route pattern.
+    #[serde(default)]
+    pub route_timeouts: BTreeMap<String, u64>,
+}
+
+impl ServerConfig {
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn route_timeouts(&self) -> impl Iterator<Item = (String, Duration)> + '_ {
+        self.route_timeouts
+            .iter()
+            .map(|(route, timeout_ms)| (route.clone(), Duration::from_millis(*timeout_ms)))
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct OtelConfig {
-    pub endpoint: String,
+    pub endpoint: Endpoints,
+    /// Per-signal overrides of `endpoint`.
+    pub traces_endpoint: Option<Endpoints>,
+    pub metrics_endpoint: Option<Endpoints>,
+    pub logs_endpoint: Option<Endpoints>,
+    /// Merge host, OS and process attributes into the resource. Detection runs
+    /// once at startup.
+    #[serde(default)]
+    pub resource_detectors: bool,
+    /// Targets filter for spans exported over OTLP, e.g. `"info,h2=off,hyper=off"`.
+    /// Defaults to every target at INFO and above.
+    pub trace_filter: Option<String>,
+    /// Least severe level of the logs written to stdout and exported, e.g.
+    /// `"warn"`. Defaults to INFO. Spans are exported according to
+    /// `trace_filter` whatever this is set to.
+    pub log_level: Option<String>,
+    /// Format of the logs written to stdout.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Export logs emitted inside an unsampled trace to stdout only, keeping
+    /// them out of the OTLP log pipeline. Logs outside any trace are exported
+    /// either way.
+    #[serde(default)]
+    pub sampled_logs_only: bool,
+    pub tail_sampling: Option<TailSamplingConfig>,
+    /// Stop exporting spans to an endpoint that keeps failing.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// How OTLP span exporters are fed.
+    #[serde(default)]
+    pub span_processor: SpanProcessorKind,
+    /// Drop new spans, counting them in `otel.spans.dropped`, while this many
+    /// are started and not yet ended.
+    pub max_in_flight_spans: Option<usize>,
+    /// Propagators reading and writing context on requests, in order.
+    /// Defaults to W3C trace context only.
+    #[serde(default = "OtelConfig::default_propagators")]
+    pub propagators: Vec<Propagator>,
+    /// Attribute keys renamed on exported spans and HTTP metrics, e.g.
+    /// `"http.request.method" = "http.method"` for backends expecting older
+    /// semantic conventions.
+    #[serde(default)]
+    pub attribute_renames: BTreeMap<String, String>,
+    /// Send a test span and metric to the collector at startup and exit if
+    /// either isn't accepted within `connect_timeout_secs`.
+    #[serde(default)]
+    pub startup_selftest: bool,
+    /// How long OTLP exporters wait to establish a connection to the collector.
+    #[serde(default = "OtelConfig::default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// How long shutdown waits for providers to flush buffered telemetry.
+    #[serde(default = "OtelConfig::default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(default)]
+    pub metrics_config: MetricsConfig,
+    #[serde(default)]
+    pub tracing_config: TracingConfig,
+}
+
+impl OtelConfig {
+    fn default_propagators() -> Vec<Propagator> {
+        vec![Propagator::TraceContext]
+    }
+
+    fn default_connect_timeout_secs() -> u64 {
+        5
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    fn default_shutdown_timeout_secs() -> u64 {
+        5
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn traces_endpoint(&self) -> &[String] {
+        self.traces_endpoint
+            .as_ref()
+            .unwrap_or(&self.endpoint)
+            .as_slice()
+    }
+
+    pub fn metrics_endpoint(&self) -> &[String] {
+        self.metrics_endpoint
+            .as_ref()
+            .unwrap_or(&self.endpoint)
+            .as_slice()
+    }
+
+    pub fn logs_endpoint(&self) -> &[String] {
+        self.logs_endpoint
+            .as_ref()
+            .unwrap_or(&self.endpoint)
+            .as_slice()
+    }
+}
+
+/// One or more OTLP collector endpoints.
+///
+/// Every endpoint gets its own exporter, batch span processor, periodic metric
+/// reader and batch log processor, so queue memory, background export tasks and
+/// network traffic grow linearly with the number of endpoints.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Endpoints {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Endpoints {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            Endpoints::One(endpoint) => std::slice::from_ref(endpoint),
+            Endpoints::Many(endpoints) => endpoints,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+    /// `key=value` pairs, see [logfmt::Logfmt].
+    Logfmt,
+}
+
+/// Context propagation formats, see [telemetry::propagation].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Propagator {
+    /// W3C `traceparent` and `tracestate`.
+    TraceContext,
+    /// W3C `baggage`.
+    Baggage,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanProcessorKind {
+    /// Export each span as soon as it ends. Handy for local debugging, but
+    /// every span costs a round trip to the collector on the request path.
+    ///
+    /// **Not for production.** The SDK blocks the thread that ends the span
+    /// until the OTLP export completes, stalling that actix worker for every
+    /// request. A span ended on the thread running the runtime the exporter's
+    /// channel was created on (the main thread) deadlocks it, as the export
+    /// can't make progress while the thread is blocked.
+    Simple,
+    /// Queue spans and export them in batches in the background.
+    #[default]
+    Batch,
+}
+
+/// When `record_trace` records the `http.request.headers` dump.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureHeaders {
+    Never,
+    #[default]
+    Always,
+    /// Only for 4xx and 5xx responses.
+    OnError,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanLevel {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+/// Settings read by `record_trace` from the app data.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TracingConfig {
+    /// Header carrying comma-separated `traceparent` values that are attached
+    /// to the request span as links rather than as its parent.
+    pub link_header: Option<String>,
+    /// Ratio of new traces to sample; requests with a remote parent follow the
+    /// parent's decision. Unset samples everything. Shorthand for
+    /// `sampler = { parentbased_traceid_ratio = <ratio> }`.
+    pub sampling_ratio: Option<f64>,
+    /// Overrides `sampling_ratio`.
+    pub sampler: Option<SamplerConfig>,
+    /// Attributes beyond this count are dropped from a span.
+    pub max_attributes_per_span: Option<u32>,
+    /// Longest `http.request.headers` value recorded, in bytes; longer values
+    /// are truncated.
+    pub max_attribute_value_length: Option<usize>,
+    /// Attributes added to every request span, e.g. `deployment.region`.
+    /// Unlike resource attributes these can differ between apps or scopes
+    /// served by the same process.
+    #[serde(default)]
+    pub span_attributes: BTreeMap<String, String>,
+    /// Take `url.scheme` and `tls` from `Forwarded`/`X-Forwarded-Proto`
+    /// instead of the listener. Only safe behind a proxy that sets them.
+    #[serde(default)]
+    pub trust_forwarded_proto: bool,
+    /// Level of the request span, which `trace_filter` has to let through for
+    /// it to be exported. `log_level` doesn't apply to spans.
+    #[serde(default)]
+    pub span_level: SpanLevel,
+    /// Mark spans of 4xx responses as errors. Off by default, as the HTTP
+    /// semantic conventions leave client errors unset on server spans.
+    #[serde(default)]
+    pub treat_4xx_as_error: bool,
+    /// Record the `EndUserId` request extension as `enduser.id`. Off by
+    /// default since user ids are usually personal data.
+    #[serde(default)]
+    pub record_enduser_id: bool,
+    /// Record `rpc.grpc.status_code` from the `grpc-status` response header
+    /// and fail the span on a non-OK status, for services proxying gRPC.
+    #[serde(default)]
+    pub grpc: bool,
+    /// Fraction of requests to each route, keyed by route pattern, that are
+    /// traced, e.g. `"/health" = 0.001` to trace health checks only
+    /// occasionally. The rest export no spans at all, handler spans included.
+    /// Routes not listed are always traced.
+    #[serde(default)]
+    pub route_sample_rates: BTreeMap<String, f64>,
+    /// Return the trace id of a 5xx response in an `x-trace-id` header, so
+    /// it can be quoted when reporting the failure.
+    #[serde(default)]
+    pub expose_trace_id: bool,
+    /// Requests declaring a `Content-Length` above this many bytes aren't
+    /// counted as the handler reads them; `http.request.body.size` is taken
+    /// from the header instead. That saves per-chunk work on large uploads,
+    /// but the size is what the client declared rather than what was read, and
+    /// `http.request.body.length_mismatch` is never set for them. Unset counts
+    /// every body.
+    pub max_counted_body_size: Option<u64>,
+    /// When to record the `http.request.headers` dump, with `Authorization`,
+    /// `Cookie` and `Proxy-Authorization` values redacted. Defaults to always.
+    #[serde(default)]
+    pub capture_headers: CaptureHeaders,
+    /// Longest `http.request.headers` dump recorded, in bytes, before
+    /// `...[truncated]` is appended. Applies before
+    /// `max_attribute_value_length`.
+    pub max_header_capture_bytes: Option<usize>,
+    /// Record `url.path` with the segments matched by route parameters
+    /// replaced by their `{name}`, keeping ids and other personal data out of
+    /// spans.
+    #[serde(default)]
+    pub template_url_path: bool,
+    /// Don't trace requests that match no route, e.g. scanners probing for
+    /// paths that don't exist. They export no spans at all, including any a
+    /// default service starts.
+    #[serde(default)]
+    pub trace_only_matched_routes: bool,
+}
+
+impl TracingConfig {
+    pub fn sampler(&self) -> SamplerConfig {
+        match (self.sampler, self.sampling_ratio) {
+            (Some(sampler), _) => sampler,
+            (None, Some(ratio)) => SamplerConfig::ParentBasedTraceIdRatio(ratio),
+            (None, None) => SamplerConfig::ParentBasedAlwaysOn,
+        }
+    }
+
+    pub fn sampling_ratio(&self) -> f64 {
+        self.sampler().ratio()
+    }
+}
+
+/// The standard `OTEL_TRACES_SAMPLER` modes.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum SamplerConfig {
+    #[serde(rename = "always_on")]
+    AlwaysOn,
+    #[serde(rename = "always_off")]
+    AlwaysOff,
+    #[serde(rename = "traceid_ratio")]
+    TraceIdRatio(f64),
+    #[serde(rename = "parentbased_always_on")]
+    ParentBasedAlwaysOn,
+    #[serde(rename = "parentbased_traceid_ratio")]
+    ParentBasedTraceIdRatio(f64),
+}
+
+impl SamplerConfig {
+    /// Reads `OTEL_TRACES_SAMPLER` and `OTEL_TRACES_SAMPLER_ARG`.
+    pub fn from_env() -> Option<Self> {
+        let sampler = std::env::var("OTEL_TRACES_SAMPLER").ok()?;
+        let arg = std::env::var("OTEL_TRACES_SAMPLER_ARG").ok();
+        Self::parse(&sampler, arg.as_deref())
+    }
+
+    /// Parses an `OTEL_TRACES_SAMPLER` value. A missing or invalid ratio
+    /// argument defaults to 1.0, as the specification requires.
+    pub fn parse(sampler: &str, arg: Option<&str>) -> Option<Self> {
+        let ratio = || {
+            arg.and_then(|arg| arg.trim().parse::<f64>().ok())
+                .filter(|ratio| (0.0..=1.0).contains(ratio))
+                .unwrap_or(1.0)
+        };
+        match sampler.trim() {
+            "always_on" => Some(SamplerConfig::AlwaysOn),
+            "always_off" => Some(SamplerConfig::AlwaysOff),
+            "traceidratio" => Some(SamplerConfig::TraceIdRatio(ratio())),
+            "parentbased_always_on" => Some(SamplerConfig::ParentBasedAlwaysOn),
+            "parentbased_traceidratio" => Some(SamplerConfig::ParentBasedTraceIdRatio(ratio())),
+            _ => None,
+        }
+    }
+
+    /// The ratio of new root traces this sampler keeps.
+    pub fn ratio(&self) -> f64 {
+        match self {
+            SamplerConfig::AlwaysOn | SamplerConfig::ParentBasedAlwaysOn => 1.0,
+            SamplerConfig::AlwaysOff => 0.0,
+            SamplerConfig::TraceIdRatio(ratio) | SamplerConfig::ParentBasedTraceIdRatio(ratio) => {
+                *ratio
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub duration_unit: DurationUnit,
+    /// Export process memory and CPU gauges. Stats are read from the OS on
+    /// every collection, so this is off by default.
+    #[serde(default)]
+    pub process_metrics: bool,
+    /// Export `otel.spans.active` and `otel.exporter.queue.size` gauges for
+    /// the span pipeline.
+    #[serde(default)]
+    pub pipeline_metrics: bool,
+    /// Attempts per OTLP metrics export before the batch is dropped. Defaults
+    /// to 3.
+    pub export_attempts: Option<u32>,
+    /// Wait before the first retry, doubled after each further failure.
+    /// Defaults to 500ms.
+    pub export_backoff_ms: Option<u64>,
+    /// Record `http.server.active_requests`. Defaults to true.
+    pub active_requests: Option<bool>,
+    /// Resource attributes, e.g. `deployment.environment`, also recorded on
+    /// every HTTP metric data point, for backends that don't join resource
+    /// attributes onto metrics.
+    #[serde(default)]
+    pub promote_resource_attributes: Vec<String>,
+    /// Take `url.scheme` from `Forwarded`/`X-Forwarded-Proto` instead of the
+    /// listener, like `tracing_config.trust_forwarded_proto` does for spans.
+    #[serde(default)]
+    pub trust_forwarded_proto: bool,
+    /// Log a `slow_request` warning for requests taking longer than this.
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Record the request's base `Content-Type` as
+    /// `http.request.header.content_type`; uncommon types are recorded as
+    /// `_OTHER`.
+    #[serde(default)]
+    pub content_type_attribute: bool,
+    /// Tenants given a meter scope of their own, see [TenantMeters]; the
+    /// metrics of any further tenants go to the default scope. Defaults to 100.
+    pub max_tenants: Option<usize>,
+}
+
+impl MetricsConfig {
+    pub fn export_attempts(&self) -> u32 {
+        self.export_attempts.unwrap_or(3).max(1)
+    }
+
+    pub fn export_backoff(&self) -> Duration {
+        Duration::from_millis(self.export_backoff_ms.unwrap_or(500))
+    }
+
+    pub fn active_requests(&self) -> bool {
+        self.active_requests.unwrap_or(true)
+    }
+
+    pub fn slow_request_threshold(&self) -> Option<Duration> {
+        self.slow_request_threshold_ms.map(Duration::from_millis)
+    }
+
+    pub fn max_tenants(&self) -> usize {
+        self.max_tenants.unwrap_or(DEFAULT_MAX_TENANTS)
+    }
+}
+
+/// Unit of `http.server.duration`; both the recorded value and the
+/// instrument's unit annotation follow it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum DurationUnit {
+    #[default]
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+}
+
+impl DurationUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DurationUnit::Seconds => "s",
+            DurationUnit::Milliseconds => "ms",
+        }
+    }
+
+    pub fn convert(&self, duration: Duration) -> f64 {
+        match self {
+            DurationUnit::Seconds => duration.as_secs_f64(),
+            DurationUnit::Milliseconds => duration.as_secs_f64() * 1_000.0,
+        }
+    }
+}
+
+/// Settings for the in-process tail-sampling buffer.
+///
+/// Spans are held per trace until the local root span ends or `window_ms`
+/// elapses, whichever comes first. At most `max_traces` traces of at most
+/// `max_spans_per_trace` spans each are buffered; the oldest trace is decided
+/// early when the buffer is full, and spans beyond the per-trace cap are dropped.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TailSamplingConfig {
+    #[serde(default = "TailSamplingConfig::default_window_ms")]
+    pub window_ms: u64,
+    #[serde(default = "TailSamplingConfig::default_latency_threshold_ms")]
+    pub latency_threshold_ms: u64,
+    #[serde(default = "TailSamplingConfig::default_sample_ratio")]
+    pub sample_ratio: f64,
+    #[serde(default = "TailSamplingConfig::default_max_traces")]
+    pub max_traces: usize,
+    #[serde(default = "TailSamplingConfig::default_max_spans_per_trace")]
+    pub max_spans_per_trace: usize,
+}
+
+impl TailSamplingConfig {
+    fn default_window_ms() -> u64 {
+        10_000
+    }
+
+    fn default_latency_threshold_ms() -> u64 {
+        1_000
+    }
+
+    fn default_sample_ratio() -> f64 {
+        0.1
+    }
+
+    fn default_max_traces() -> usize {
+        1_024
+    }
+
+    fn default_max_spans_per_trace() -> usize {
+        256
+    }
+}
+
+impl Default for TailSamplingConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: Self::default_window_ms(),
+            latency_threshold_ms: Self::default_latency_threshold_ms(),
+            sample_ratio: Self::default_sample_ratio(),
+            max_traces: Self::default_max_traces(),
+            max_spans_per_trace: Self::default_max_spans_per_trace(),
+        }
+    }
+}
+
+/// Settings for the circuit breaker in front of each OTLP span exporter.
+///
+/// After `failure_threshold` consecutive failed exports the breaker opens and
+/// spans are dropped without contacting the collector. Once `open_ms` has
+/// passed, the next batch is sent as a probe: success closes the breaker,
+/// failure keeps it open for another `open_ms`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "CircuitBreakerConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "CircuitBreakerConfig::default_open_ms")]
+    pub open_ms: u64,
+}
+
+impl CircuitBreakerConfig {
+    fn default_failure_threshold() -> u32 {
+        5
+    }
+
+    fn default_open_ms() -> u64 {
+        30_000
+    }
+
+    pub fn open_duration(&self) -> Duration {
+        Duration::from_millis(self.open_ms)
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: Self::default_failure_threshold(),
+            open_ms: Self::default_open_ms(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::middleware::tracing::{record_trace, TraceInfo};
+    use crate::testing::TestTelemetry;
+    use crate::{
+        AppConfig, AppContext, ConfigError, DurationUnit, Endpoints, LogFormat, OtelConfig,
+        RandomConfig, SamplerConfig, TracingConfig,
+    };
+    use actix_web::middleware::from_fn;
+    use actix_web::{web, App, HttpResponse};
+    use opentelemetry::trace::{Span as _, Tracer as _};
+    use opentelemetry::{KeyValue, Value};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    /// Writes `files` into a fresh directory under the system temp dir.
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("app-config-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    const BASE_CONFIG: &str = r#"
+        [otel_config]
+        endpoint = "http://localhost:4317"
+        resource_detectors = false
+
+        [server_config]
+        workers = 2
+        "#;
+
+    #[test]
+    fn test_load_base_only() {
+        let dir = config_dir(
+            "base-only",
+            &[
+                ("app.toml", BASE_CONFIG),
+                ("app.production.toml", "[server_config]\nworkers = 8"),
+            ],
+        );
+        let app_config = AppConfig::load(dir.join("app.toml"), None).unwrap();
+        assert_eq!(app_config.server_config.workers, Some(2));
+
+        let app_config = AppConfig::load(dir.join("app.toml"), Some("staging")).unwrap();
+        assert_eq!(app_config.server_config.workers, Some(2));
+    }
+
+    #[test]
+    fn test_load_with_overlay() {
+        let dir = config_dir(
+            "overlay",
+            &[
+                ("app.toml", BASE_CONFIG),
+                (
+                    "app.production.toml",
+                    r#"
+                    [otel_config]
+                    endpoint = "http://collector:4317"
+
+                    [server_config]
+                    backlog = 4096
+                    "#,
+                ),
+            ],
+        );
+        let app_config = AppConfig::load(dir.join("app.toml"), Some("production")).unwrap();
+        assert_eq!(
+            app_config.otel_config.endpoint.as_slice(),
+            ["http://collector:4317"]
+        );
+        assert!(!app_config.otel_config.resource_detectors);
+        assert_eq!(app_config.server_config.workers, Some(2));
+        assert_eq!(app_config.server_config.backlog, Some(4096));
+    }
+
+    #[test]
+    fn test_server_config() {
+        let app_config = toml::from_str::<AppConfig>(
+            r#"
+            [otel_config]
+            endpoint = "http://localhost:4317"
+
+            [server_config]
+            workers = 4
+            backlog = 512
+            max_body_size = 1024
+            "#,
+        )
+        .unwrap();
+        assert_eq!(app_config.server_config.workers, Some(4));
+        assert_eq!(app_config.server_config.backlog, Some(512));
+        assert_eq!(app_config.server_config.max_body_size, Some(1024));
+    }
+
+    #[test]
+    fn test_server_config_defaults() {
+        let app_config = toml::from_str::<AppConfig>(
+            r#"
+            [otel_config]
+            endpoint = "http://localhost:4317"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(app_config.server_config.workers, None);
+        assert_eq!(app_config.server_config.backlog, None);
+        assert_eq!(app_config.server_config.max_body_size, None);
+    }
+
+    #[test]
+    fn test_duration_unit_convert() {
+        let duration = Duration::from_millis(1_500);
+        assert_eq!(DurationUnit::Seconds.convert(duration), 1.5);
+        assert_eq!(DurationUnit::Milliseconds.convert(duration), 1_500.0);
+    }
+
+    #[test]
+    fn test_multiple_endpoints() {
+        let app_config = toml::from_str::<AppConfig>(
+            r#"
+            [otel_config]
+            endpoint = ["http://localhost:4317", "http://localhost:14317"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            app_config.otel_config.endpoint.as_slice(),
+            ["http://localhost:4317", "http://localhost:14317"]
+        );
+        assert_eq!(
+            Endpoints::One("http://localhost:4317".to_string()).as_slice(),
+            ["http://localhost:4317"]
+        );
+    }
+
+    #[test]
+    fn test_per_signal_endpoints() {
+        let app_config = toml::from_str::<AppConfig>(
+            r#"
+            [otel_config]
+            endpoint = "http://localhost:4317"
+            traces_endpoint = "http://traces:4317"
+            metrics_endpoint = ["http://metrics-a:4317", "http://metrics-b:4317"]
+            "#,
+        )
+        .unwrap();
+        let otel_config = app_config.otel_config;
+        assert_eq!(otel_config.traces_endpoint(), ["http://traces:4317"]);
+        assert_eq!(
+            otel_config.metrics_endpoint(),
+            ["http://metrics-a:4317", "http://metrics-b:4317"]
+        );
+        assert_eq!(otel_config.logs_endpoint(), ["http://localhost:4317"]);
+    }
+
+    #[test]
+    fn test_sampler_config() {
+        let parse = |toml: &str| toml::from_str::<TracingConfig>(toml).unwrap().sampler();
+        assert_eq!(parse(r#"sampler = "always_off""#), SamplerConfig::AlwaysOff);
+        assert_eq!(
+            parse(r#"sampler = { traceid_ratio = 0.1 }"#),
+            SamplerConfig::TraceIdRatio(0.1)
+        );
+        assert_eq!(
+            parse("sampling_ratio = 0.5"),
+            SamplerConfig::ParentBasedTraceIdRatio(0.5)
+        );
+        assert_eq!(parse(""), SamplerConfig::ParentBasedAlwaysOn);
+    }
+
+    #[test]
+    fn test_sampler_from_env_values() {
+        assert_eq!(
+            SamplerConfig::parse("parentbased_traceidratio", Some("0.25")),
+            Some(SamplerConfig::ParentBasedTraceIdRatio(0.25))
+        );
+        assert_eq!(
+            SamplerConfig::parse("traceidratio", Some("invalid")),
+            Some(SamplerConfig::TraceIdRatio(1.0))
+        );
+        assert_eq!(
+            SamplerConfig::parse("always_on", None),
+            Some(SamplerConfig::AlwaysOn)
+        );
+        assert_eq!(SamplerConfig::parse("jaeger_remote", None), None);
+    }
+
+    #[test]
+    fn test_log_format() {
+        let parse = |toml: &str| toml::from_str::<OtelConfig>(toml).unwrap().log_format;
+        assert_eq!(
+            parse(r#"endpoint = "http://localhost:4317""#),
+            LogFormat::Compact
+        );
+        assert_eq!(
+            parse(
+                r#"
+                endpoint = "http://localhost:4317"
+                log_format = "logfmt"
+                "#
+            ),
+            LogFormat::Logfmt
+        );
+    }
+
+    #[test]
+    fn test_random_config() {
+        let app_config = toml::from_str::<AppConfig>(
+            r#"
+            [otel_config]
+            endpoint = "http://localhost:4317"
+
+            [random_config]
+            min = 100
+            max = 250
+            unit = "ms"
+            "#,
+        )
+        .unwrap();
+        let config = app_config.random_config;
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..100 {
+            let value = config.sample(&mut rng);
+            assert!((100..250).contains(&value));
+            assert_eq!(config.duration(value), Duration::from_millis(value));
+        }
+
+        let default = RandomConfig::default();
+        assert_eq!((default.min, default.max), (1, 5));
+        assert_eq!(default.duration(2), Duration::from_secs(2));
+    }
+
+    #[tracing::instrument(skip_all, parent = trace_info.app_root_span.clone())]
+    async fn checkout(
+        context: web::Data<AppContext>,
+        trace_info: web::ReqData<TraceInfo>,
+    ) -> HttpResponse {
+        context.record_dependency_health();
+        HttpResponse::Ok().finish()
+    }
+
+    #[tokio::test]
+    async fn test_record_dependency_health() {
+        let telemetry = TestTelemetry::new();
+        let _guard = telemetry.set_default();
+
+        let mut context = AppContext::new(telemetry.meter_provider());
+        context
+            .register_dependency("db", || true)
+            .register_dependency("payments", || false);
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(from_fn(record_trace))
+                .route("/checkout", web::post().to(checkout)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/checkout")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = telemetry.collect().spans;
+        let handler_span = spans.iter().find(|span| span.name == "checkout").unwrap();
+        let attribute = |key: &str| {
+            handler_span
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(attribute("db.healthy"), Some(Value::Bool(true)));
+        assert_eq!(attribute("payments.healthy"), Some(Value::Bool(false)));
+    }
+
+    async fn manual_span(
+        context: web::Data<AppContext>,
+        trace_info: web::ReqData<TraceInfo>,
+    ) -> HttpResponse {
+        let parent = trace_info.app_root_span.context();
+        let mut span = context.tracer().start_with_context("manual", &parent);
+        span.set_attribute(KeyValue::new("step", "lookup"));
+        span.end();
+        HttpResponse::Ok().finish()
+    }
+
+    #[tokio::test]
+    async fn test_manual_span_parented_to_request() {
+        let telemetry = TestTelemetry::new();
+        let _guard = telemetry.set_default();
+
+        let context = AppContext::new(telemetry.meter_provider()).with_tracer(telemetry.tracer());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(from_fn(record_trace))
+                .route("/manual", web::get().to(manual_span)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/manual")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = telemetry.collect().spans;
+        let request_span = spans
+            .iter()
+            .find(|span| span.name == "GET /manual")
+            .unwrap();
+        let manual = spans.iter().find(|span| span.name == "manual").unwrap();
+        assert_eq!(manual.parent_span_id, request_span.span_context.span_id());
+        assert_eq!(
+            manual.span_context.trace_id(),
+            request_span.span_context.trace_id()
+        );
+        assert!(manual.attributes.contains(&KeyValue::new("step", "lookup")));
+    }
+
+    #[tokio::test]
+    async fn test_check_config() {
+        let dir = config_dir(
+            "check",
+            &[
+                ("app.toml", BASE_CONFIG),
+                (
+                    "app.broken.toml",
+                    r#"
+                    [otel_config]
+                    endpoint = "not a url"
+                    trace_filter = "info,=bogus="
+
+                    [otel_config.tracing_config]
+                    sampling_ratio = 1.5
+                    "#,
+                ),
+            ],
+        );
+        assert!(AppConfig::check(dir.join("app.toml"), None).is_empty());
+
+        let problems = AppConfig::check(dir.join("app.toml"), Some("broken"));
+        assert!(problems
+            .iter()
+            .any(|problem| problem.contains("sampling ratio 1.5")));
+        assert!(problems
+            .iter()
+            .any(|problem| problem.starts_with("invalid trace_filter")));
+        #[cfg(feature = "otlp")]
+        assert!(problems
+            .iter()
+            .any(|problem| problem.starts_with("invalid OTLP traces endpoint \"not a url\"")));
+
+        let problems = AppConfig::check(dir.join("missing.toml"), None);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("failed to read"));
+    }
+
+    #[test]
+    fn test_load_malformed_toml() {
+        let dir = config_dir(
+            "malformed",
+            &[(
+                "app.toml",
+                "[otel_config]\nendpoint = \"http://localhost:4317\nresource_detectors = false\n",
+            )],
+        );
+        let err = AppConfig::load(dir.join("app.toml"), None).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }));
+        let message = err.to_string();
+        assert!(message.starts_with(&format!(
+            "failed to parse {}: TOML parse error at line 2, column",
+            dir.join("app.toml").display()
+        )));
+        assert!(message.contains("endpoint = \"http://localhost:4317"));
+    }
+
+    #[test]
+    fn test_load_invalid_overlay_value() {
+        let dir = config_dir(
+            "invalid-overlay",
+            &[
+                ("app.toml", BASE_CONFIG),
+                ("app.production.toml", "[server_config]\nworkers = \"four\""),
+            ],
+        );
+        let message = AppConfig::load(dir.join("app.toml"), Some("production"))
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("app.production.toml"));
+        assert!(message.contains("server_config.workers"));
+    }
 }
@@ -0,0 +1,190 @@
+use opentelemetry::trace::{TraceContextExt, TraceId};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Renders events as logfmt, e.g.
+/// `ts=2024-01-01T00:00:00.000000Z level=info target=app trace_id=0af7... msg="request completed" http.route=/`.
+///
+/// `trace_id` is taken from the innermost span tracked by the OpenTelemetry
+/// layer and omitted when there is none.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Logfmt;
+
+impl<S, N> FormatEvent<S, N> for Logfmt
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        write!(writer, "ts=")?;
+        SystemTime.format_time(&mut writer)?;
+        write!(
+            writer,
+            " level={} target={}",
+            metadata.level().as_str().to_lowercase(),
+            quote(metadata.target())
+        )?;
+        if let Some(trace_id) = ctx
+            .lookup_current()
+            .and_then(|span| span.extensions().get::<OtelData>().map(trace_id))
+        {
+            write!(writer, " trace_id={trace_id}")?;
+        }
+
+        let mut visitor = LogfmtVisitor {
+            writer: &mut writer,
+            result: Ok(()),
+        };
+        event.record(&mut visitor);
+        visitor.result?;
+        writeln!(writer)
+    }
+}
+
+/// The trace id the span will be exported with: its parent's when it has one,
+/// otherwise the id generated for the new root.
+fn trace_id(otel_data: &OtelData) -> TraceId {
+    let parent = otel_data.parent_cx.span();
+    let parent = parent.span_context();
+    if parent.is_valid() {
+        parent.trace_id()
+    } else {
+        otel_data.builder.trace_id.unwrap_or(TraceId::INVALID)
+    }
+}
+
+struct LogfmtVisitor<'a, 'w> {
+    writer: &'a mut Writer<'w>,
+    result: fmt::Result,
+}
+
+impl Visit for LogfmtVisitor<'_, '_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.write(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.write(field, &format!("{value:?}"));
+    }
+}
+
+impl LogfmtVisitor<'_, '_> {
+    fn write(&mut self, field: &Field, value: &str) {
+        if self.result.is_ok() {
+            let key = match field.name() {
+                "message" => "msg",
+                name => name,
+            };
+            self.result = write!(self.writer, " {}={}", key, quote(value));
+        }
+    }
+}
+
+/// Quotes and escapes `value` when it can't be written bare.
+fn quote(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '=' || c.is_control());
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, Logfmt};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_logfmt_event() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test_tracer")))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(Logfmt)
+                    .with_writer(move || writer.clone()),
+            )
+            .set_default();
+
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info!(
+                http.route = "/error/{code}",
+                status = 503,
+                "request \"completed\""
+            );
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let trace_id = exporter.get_finished_spans().unwrap()[0]
+            .span_context
+            .trace_id();
+        let line = output.trim_end();
+        assert!(line.starts_with("ts="));
+        assert!(line.contains(" level=info target=actix_otel_example::logfmt::tests "));
+        assert!(line.contains(&format!(" trace_id={trace_id} ")));
+        assert!(
+            line.ends_with(r#" msg="request \"completed\"" http.route=/error/{code} status=503"#)
+        );
+    }
+
+    #[test]
+    fn test_quote_control_characters() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote("a\r\nb\tc"), r#""a\r\nb\tc""#);
+        assert_eq!(quote("bell\u{7}"), r#""bell\u{0007}""#);
+    }
+}
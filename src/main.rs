@@ -1,41 +1,114 @@
 use actix_otel_example::api::route;
+use actix_otel_example::metrics::{LatencyWindow, TenantMeters};
+use actix_otel_example::middleware::access_log::access_log;
+use actix_otel_example::middleware::body_limit::BodyLimit;
 use actix_otel_example::middleware::metrics::HttpMetrics;
-use actix_otel_example::middleware::tracing::record_trace;
-use actix_otel_example::telemetry::{build_metrics_provider, init_subscriber};
-use actix_otel_example::{AppConfig, AppContext};
-use actix_web::middleware::{from_fn, Logger};
+use actix_otel_example::middleware::timeout::RequestTimeout;
+use actix_otel_example::middleware::tracing::{record_trace, WorkerIndex};
+use actix_otel_example::telemetry::{
+    build_metrics_provider, build_resource, init_subscriber, selftest, shutdown_providers,
+    AttributeRenames,
+};
+use actix_otel_example::{AppConfig, AppContext, SamplerConfig, METER_NAME};
+use actix_web::middleware::{from_fn, Condition};
 use actix_web::{web, App, HttpServer};
-use opentelemetry::global;
-use opentelemetry::global::shutdown_tracer_provider;
-use std::fs;
+use opentelemetry::metrics::MeterProvider as _;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let app_config = fs::read_to_string("app.toml")
-        .ok()
-        .and_then(|value| toml::from_str::<AppConfig>(&value).ok())
-        .expect("failed to read app.toml");
+    let app_env = env::var("APP_ENV").ok();
+    if env::args().skip(1).any(|arg| arg == "--check-config") {
+        let problems = AppConfig::check("app.toml", app_env.as_deref());
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        println!("app.toml is valid");
+        return Ok(());
+    }
+    let mut app_config = match AppConfig::load("app.toml", app_env.as_deref()) {
+        Ok(app_config) => app_config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(sampler) = SamplerConfig::from_env() {
+        app_config.otel_config.tracing_config.sampler = Some(sampler);
+    }
 
-    init_subscriber(&app_config.otel_config);
     let meter_provider = build_metrics_provider(&app_config.otel_config);
-    global::set_meter_provider(meter_provider.clone());
-    let meter = Arc::new(global::meter("rust-telemetry-example"));
+    let logger_provider = init_subscriber(&app_config.otel_config, &meter_provider);
+    if app_config.otel_config.startup_selftest {
+        let problems = selftest(&app_config.otel_config).await;
+        for problem in &problems {
+            tracing::error!("{problem}");
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        tracing::info!("OTLP self-test passed");
+    }
+    let meter = Arc::new(meter_provider.meter(METER_NAME));
 
-    HttpServer::new(move || {
+    let resource = build_resource(&app_config.otel_config);
+    let attribute_renames = AttributeRenames::new(&app_config.otel_config.attribute_renames);
+    let max_body_size = app_config.server_config.max_body_size;
+    let request_timeout = RequestTimeout::new(app_config.server_config.request_timeout())
+        .with_route_timeouts(app_config.server_config.route_timeouts());
+    let shutdown_timeout = app_config.otel_config.shutdown_timeout();
+    let app_meter_provider = meter_provider.clone();
+    let latency_window = Arc::new(LatencyWindow::default());
+    let tenant_meters = Arc::new(
+        TenantMeters::new(meter_provider.clone())
+            .with_max_tenants(app_config.otel_config.metrics_config.max_tenants()),
+    );
+    let next_worker_index = Arc::new(AtomicUsize::new(0));
+    let http_metrics =
+        HttpMetrics::new(meter.clone(), app_config.otel_config.metrics_config.clone())
+            .with_latency_window(latency_window.clone())
+            .with_resource(&resource)
+            .with_attribute_renames(attribute_renames.clone());
+    http_metrics.register_instruments();
+    let mut server = HttpServer::new(move || {
+        // Called once per worker, on that worker's thread.
+        let worker_index = next_worker_index.fetch_add(1, Ordering::Relaxed);
         App::new()
-            .app_data(web::Data::new(AppContext::new(meter.clone())))
-            .wrap(Logger::default())
+            .app_data(web::Data::new(WorkerIndex(worker_index)))
+            .app_data(web::Data::new(
+                AppContext::new(app_meter_provider.clone())
+                    .with_random_config(app_config.random_config.clone())
+                    .with_latency_window(latency_window.clone())
+                    .with_tenant_meters(tenant_meters.clone()),
+            ))
+            .app_data(web::Data::new(
+                app_config.otel_config.tracing_config.clone(),
+            ))
+            .wrap(request_timeout.clone())
+            .wrap(Condition::new(
+                max_body_size.is_some(),
+                BodyLimit::new(meter.clone(), max_body_size.unwrap_or(u64::MAX)),
+            ))
+            // Wrapped before record_trace so it runs inside it and sees TraceInfo.
+            .wrap(from_fn(access_log))
             .wrap(from_fn(record_trace))
-            .wrap(HttpMetrics::new(meter.clone()))
+            .wrap(http_metrics.clone())
             .configure(route)
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await?;
+    });
+    if let Some(workers) = app_config.server_config.workers {
+        server = server.workers(workers);
+    }
+    if let Some(backlog) = app_config.server_config.backlog {
+        server = server.backlog(backlog);
+    }
+    server.bind(("127.0.0.1", 8080))?.run().await?;
 
-    tokio::task::spawn_blocking(shutdown_tracer_provider);
-    tokio::task::spawn_blocking(move || meter_provider.shutdown());
+    shutdown_providers(meter_provider, logger_provider, shutdown_timeout).await;
 
     Ok(())
 }
@@ -0,0 +1,250 @@
+use crate::middleware::metrics::Metrics;
+use opentelemetry::metrics::{Meter, MeterProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+
+/// A typed handle to an instrument stored in a [MetricsRegistry].
+///
+/// The type parameter is the instrument type (e.g. `Counter<f64>`), so looking
+/// up a key always yields the instrument it was registered with.
+#[derive(Debug)]
+pub struct MetricKey<I> {
+    name: &'static str,
+    _instrument: PhantomData<fn() -> I>,
+}
+
+impl<I> MetricKey<I> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _instrument: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Instruments registered once at startup and shared by handlers.
+#[derive(Debug)]
+pub struct MetricsRegistry {
+    meter: Arc<Meter>,
+    instruments: HashMap<&'static str, Box<dyn Any + Send + Sync>>,
+}
+
+impl MetricsRegistry {
+    pub fn new(meter: Arc<Meter>) -> Self {
+        Self {
+            meter,
+            instruments: HashMap::new(),
+        }
+    }
+
+    pub fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
+    /// Builds the instrument for `key` with the registry's meter. Registering
+    /// the same key twice replaces the previous instrument.
+    pub fn register<I, F>(&mut self, key: &MetricKey<I>, build: F) -> &mut Self
+    where
+        I: Send + Sync + 'static,
+        F: FnOnce(&Meter, &'static str) -> I,
+    {
+        let instrument = build(&self.meter, key.name);
+        self.instruments.insert(key.name, Box::new(instrument));
+        self
+    }
+
+    pub fn get<I: 'static>(&self, key: &MetricKey<I>) -> Option<&I> {
+        self.instruments
+            .get(key.name)
+            .and_then(|instrument| instrument.downcast_ref::<I>())
+    }
+}
+
+/// The durations of the most recent requests, kept in memory so percentiles
+/// can be inspected locally without a metrics backend.
+#[derive(Debug)]
+pub struct LatencyWindow {
+    capacity: usize,
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl Default for LatencyWindow {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl LatencyWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Adds `duration`, evicting the oldest sample once the window is full.
+    pub fn record(&self, duration: Duration) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(duration);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Nearest-rank percentiles of the window for each quantile in `0.0..=1.0`,
+    /// or `None` if nothing has been recorded.
+    pub fn percentiles<const N: usize>(&self, quantiles: [f64; N]) -> Option<[Duration; N]> {
+        let mut sorted = self
+            .samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        Some(quantiles.map(|quantile| {
+            let rank = (quantile.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+            sorted[rank.saturating_sub(1)]
+        }))
+    }
+}
+
+/// Meters with one instrumentation scope per tenant, named
+/// `{METER_NAME}.tenant.{tenant}`, so each tenant's metrics are exported as a
+/// stream of their own.
+///
+/// Every scope keeps its own instruments and aggregation state and adds a
+/// scope to each export, and none of it is freed before the provider shuts
+/// down; scope names are leaked as the SDK wants `&'static str`. Use it for a
+/// bounded set of tenants, and attributes for anything open-ended. At most
+/// `max_tenants` scopes are created; later tenants get none.
+#[derive(Debug)]
+pub struct TenantMeters {
+    meter_provider: SdkMeterProvider,
+    max_tenants: usize,
+    scopes: Mutex<HashMap<String, Arc<TenantScope>>>,
+}
+
+/// A tenant's meter and the HTTP instruments built from it.
+#[derive(Debug)]
+struct TenantScope {
+    meter: Arc<Meter>,
+    http_metrics: OnceLock<Metrics>,
+}
+
+impl TenantMeters {
+    pub fn new(meter_provider: SdkMeterProvider) -> Self {
+        Self {
+            meter_provider,
+            max_tenants: DEFAULT_MAX_TENANTS,
+            scopes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_max_tenants(mut self, max_tenants: usize) -> Self {
+        self.max_tenants = max_tenants;
+        self
+    }
+
+    /// The meter scoped to `tenant`, created on first use, or `None` once
+    /// `max_tenants` other tenants have one. Record their metrics with the
+    /// default meter instead.
+    pub fn meter(&self, tenant: &str) -> Option<Arc<Meter>> {
+        self.scope(tenant).map(|scope| scope.meter.clone())
+    }
+
+    /// [HttpMetrics](crate::middleware::metrics::HttpMetrics)' instruments
+    /// on `tenant`'s meter, built by `build` only the first time, so each
+    /// request reuses them as with the default meter. `None` past the tenant
+    /// limit, like [TenantMeters::meter].
+    pub(crate) fn http_metrics(
+        &self,
+        tenant: &str,
+        build: impl FnOnce(Arc<Meter>) -> Metrics,
+    ) -> Option<Metrics> {
+        let scope = self.scope(tenant)?;
+        Some(
+            scope
+                .http_metrics
+                .get_or_init(|| build(scope.meter.clone()))
+                .clone(),
+        )
+    }
+
+    fn scope(&self, tenant: &str) -> Option<Arc<TenantScope>> {
+        let mut scopes = self.scopes.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(scope) = scopes.get(tenant) {
+            return Some(scope.clone());
+        }
+        if scopes.len() >= self.max_tenants {
+            return None;
+        }
+        let name = format!("{}.tenant.{tenant}", crate::METER_NAME);
+        let meter = Arc::new(self.meter_provider.versioned_meter(
+            Box::leak(name.into_boxed_str()),
+            None,
+            None,
+            Some(vec![KeyValue::new("tenant.id", tenant.to_string())]),
+        ));
+        let scope = Arc::new(TenantScope {
+            meter,
+            http_metrics: OnceLock::new(),
+        });
+        scopes.insert(tenant.to_string(), scope.clone());
+        Some(scope)
+    }
+}
+
+pub const DEFAULT_MAX_TENANTS: usize = 100;
+
+#[cfg(test)]
+mod tests {
+    use super::{LatencyWindow, TenantMeters};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use std::time::Duration;
+
+    #[test]
+    fn test_latency_window_percentiles() {
+        let window = LatencyWindow::new(100);
+        assert_eq!(window.percentiles([0.5]), None);
+        for ms in (1..=150).rev() {
+            window.record(Duration::from_millis(ms));
+        }
+        assert_eq!(window.len(), 100);
+        assert_eq!(
+            window.percentiles([0.0, 0.5, 0.9, 0.99, 1.0]),
+            Some([1, 50, 90, 99, 100].map(Duration::from_millis))
+        );
+    }
+
+    #[test]
+    fn test_tenant_meters_limit() {
+        let tenant_meters = TenantMeters::new(SdkMeterProvider::default()).with_max_tenants(1);
+        assert!(tenant_meters.meter("acme").is_some());
+        assert!(tenant_meters.meter("globex").is_none());
+        assert!(tenant_meters.meter("acme").is_some());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::middleware::tracing::TraceInfo;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderName;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry_semantic_conventions::trace::{
+    HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE,
+};
+use std::time::Instant;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+const REQUEST_ID: &str = "request.id";
+const DURATION_MS: &str = "duration_ms";
+const TRACE_ID: &str = "trace_id";
+const SPAN_ID: &str = "span_id";
+
+/// Emits one structured `access_log` event per request through `tracing`, so
+/// the access log is exported along with the other OTLP logs.
+///
+/// The `trace_id` and `span_id` fields come from the [TraceInfo] inserted by
+/// `record_trace`, so this middleware must run inside it: call
+/// `.wrap(from_fn(access_log))` before `.wrap(from_fn(record_trace))`.
+/// Otherwise both fields are empty.
+pub async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let route = req.match_pattern().unwrap_or_default();
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let (trace_id, span_id) = req
+        .extensions()
+        .get::<TraceInfo>()
+        .map(|trace_info| {
+            let span_id = trace_info
+                .app_root_span
+                .context()
+                .span()
+                .span_context()
+                .span_id();
+            (trace_info.trace_id.to_string(), span_id.to_string())
+        })
+        .unwrap_or_default();
+
+    let res = next.call(req).await;
+    // actix renders errors, e.g. RequestTimeout's 504, once every middleware
+    // has returned; log the status it will send.
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+
+    tracing::event!(
+        target: "access_log",
+        tracing::Level::INFO,
+        { HTTP_REQUEST_METHOD } = method.as_str(),
+        { HTTP_ROUTE } = route,
+        { HTTP_RESPONSE_STATUS_CODE } = status.as_u16(),
+        { DURATION_MS } = start.elapsed().as_secs_f64() * 1000.0,
+        { REQUEST_ID } = request_id,
+        { TRACE_ID } = trace_id,
+        { SPAN_ID } = span_id,
+        "request completed"
+    );
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::route;
+    use crate::middleware::access_log::{access_log, DURATION_MS, REQUEST_ID, SPAN_ID, TRACE_ID};
+    use crate::middleware::tracing::record_trace;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_semantic_conventions::trace::{
+        HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::Layer;
+
+    /// Collects the fields of `access_log` events, formatted with `Debug`.
+    #[derive(Clone, Default)]
+    struct AccessLogEvents(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    struct FieldVisitor(HashMap<String, String>);
+
+    impl Visit for FieldVisitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for AccessLogEvents {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            if event.metadata().target() == "access_log" {
+                let mut visitor = FieldVisitor(HashMap::new());
+                event.record(&mut visitor);
+                self.0.lock().unwrap().push(visitor.0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_fields() {
+        let events = AccessLogEvents::default();
+        let _guard = tracing_subscriber::registry()
+            .with(events.clone())
+            .set_default();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(access_log))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/error/503")
+            .insert_header(("x-request-id", "req-42"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let fields = &events[0];
+        assert_eq!(fields[HTTP_REQUEST_METHOD], "GET");
+        assert_eq!(fields[HTTP_ROUTE], "/error/{code}");
+        assert_eq!(fields[HTTP_RESPONSE_STATUS_CODE], "503");
+        assert_eq!(fields[REQUEST_ID], "req-42");
+        assert!(fields[DURATION_MS].parse::<f64>().is_ok());
+        assert_eq!(fields["message"], "request completed");
+    }
+
+    #[tokio::test]
+    async fn test_access_log_trace_correlation() {
+        let span_exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(span_exporter.clone())
+            .build();
+        let events = AccessLogEvents::default();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test_tracer")))
+            .with(events.clone())
+            .set_default();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(access_log))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = span_exporter.get_finished_spans().unwrap();
+        let request_span = spans.iter().find(|span| span.name == "GET /").unwrap();
+        let events = events.0.lock().unwrap();
+        assert_eq!(
+            events[0][TRACE_ID],
+            request_span.span_context.trace_id().to_string()
+        );
+        assert_eq!(
+            events[0][SPAN_ID],
+            request_span.span_context.span_id().to_string()
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use crate::middleware::tracing::ErrorType;
+use crate::middleware::{bounded_route, declared_body_size};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{self, Payload, ServiceRequest};
+use actix_web::error::PayloadError;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpResponse};
+use futures_util::future::{self, LocalBoxFuture};
+use futures_util::Stream;
+use opentelemetry::metrics::{Counter, Meter};
+use opentelemetry::KeyValue;
+use opentelemetry_semantic_conventions::trace::{ERROR_TYPE, HTTP_ROUTE};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+const HTTP_SERVER_REQUEST_REJECTED: &str = "http.server.request.rejected";
+const PAYLOAD_TOO_LARGE: &str = "payload_too_large";
+
+/// Rejects requests whose body is larger than `max_size` bytes with 413.
+///
+/// A `Content-Length` over the limit is rejected before the handler runs;
+/// chunked bodies fail with [PayloadError::Overflow] once the handler reads
+/// past the limit.
+#[derive(Clone, Debug)]
+pub struct BodyLimit {
+    max_size: u64,
+    rejected: Counter<u64>,
+}
+
+impl BodyLimit {
+    pub fn new(meter: Arc<Meter>, max_size: u64) -> Self {
+        let rejected = meter
+            .u64_counter(HTTP_SERVER_REQUEST_REJECTED)
+            .with_description("Counts inbound HTTP requests rejected before completion.")
+            .init();
+        Self { max_size, rejected }
+    }
+}
+
+impl<S, B> dev::Transform<S, ServiceRequest> for BodyLimit
+where
+    S: dev::Service<ServiceRequest, Response = dev::ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = dev::ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = BodyLimitMiddleware<S>;
+    type InitError = ();
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(BodyLimitMiddleware {
+            service,
+            limit: self.clone(),
+        })
+    }
+}
+
+pub struct BodyLimitMiddleware<S> {
+    service: S,
+    limit: BodyLimit,
+}
+
+impl<S, B> dev::Service<ServiceRequest> for BodyLimitMiddleware<S>
+where
+    S: dev::Service<ServiceRequest, Response = dev::ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = dev::ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let rejected = self.limit.rejected.clone();
+        let attributes = [
+            KeyValue::new(HTTP_ROUTE, bounded_route(&req)),
+            KeyValue::new(ERROR_TYPE, PAYLOAD_TOO_LARGE),
+        ];
+
+        if declared_body_size(req.headers()).is_some_and(|size| size > self.limit.max_size) {
+            rejected.add(1, &attributes);
+            let mut res = HttpResponse::PayloadTooLarge().finish();
+            res.extensions_mut().insert(ErrorType(PAYLOAD_TOO_LARGE));
+            let res = req.into_response(res).map_into_right_body();
+            return Box::pin(future::ok(res));
+        }
+
+        let exceeded = limit_payload(&mut req, self.limit.max_size);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if exceeded.load(Ordering::Relaxed) && res.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                rejected.add(1, &attributes);
+                res.response_mut()
+                    .extensions_mut()
+                    .insert(ErrorType(PAYLOAD_TOO_LARGE));
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+struct LimitedPayload {
+    inner: Payload,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl Stream for LimitedPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.exceeded.load(Ordering::Relaxed) {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) if bytes.len() as u64 > self.remaining => {
+                self.exceeded.store(true, Ordering::Relaxed);
+                Poll::Ready(Some(Err(PayloadError::Overflow)))
+            }
+            Poll::Ready(Some(Ok(bytes))) => {
+                self.remaining -= bytes.len() as u64;
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            poll => poll,
+        }
+    }
+}
+
+fn limit_payload(req: &mut ServiceRequest, max_size: u64) -> Arc<AtomicBool> {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let payload = LimitedPayload {
+        inner: req.take_payload(),
+        remaining: max_size,
+        exceeded: exceeded.clone(),
+    };
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(payload),
+    });
+    exceeded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::route;
+    use crate::middleware::tracing::record_trace;
+    use crate::middleware::UNMATCHED_ROUTE;
+    use actix_web::http::header::CONTENT_LENGTH;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::{Key, Value};
+    use opentelemetry_sdk::metrics::data::Sum;
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::testing::metrics::InMemoryMetricsExporter;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    async fn call_echo(body: &'static str, chunked: bool) -> (StatusCode, Option<Value>, u64) {
+        let span_exporter = InMemorySpanExporter::default();
+        let tracer_provider = TracerProvider::builder()
+            .with_simple_exporter(span_exporter.clone())
+            .build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test")))
+            .set_default();
+        let metrics_exporter = InMemoryMetricsExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(
+                PeriodicReader::builder(
+                    metrics_exporter.clone(),
+                    opentelemetry_sdk::runtime::TokioCurrentThread,
+                )
+                .build(),
+            )
+            .build();
+        let meter = Arc::new(meter_provider.meter("test"));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(BodyLimit::new(meter, 8))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let mut req = test::TestRequest::post()
+            .uri("/echo")
+            .set_payload(body)
+            .to_request();
+        if chunked {
+            req.headers_mut().remove(CONTENT_LENGTH);
+        }
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status();
+        drop(resp);
+
+        let spans = span_exporter.get_finished_spans().unwrap();
+        let request_span = spans.iter().find(|span| span.name == "POST /echo").unwrap();
+        let error_type = request_span
+            .attributes
+            .iter()
+            .find(|kv| kv.key == Key::from_static_str(ERROR_TYPE))
+            .map(|kv| kv.value.clone());
+
+        meter_provider.force_flush().unwrap();
+        let rejections = metrics_exporter
+            .get_finished_metrics()
+            .unwrap()
+            .iter()
+            .flat_map(|resource_metrics| resource_metrics.scope_metrics.iter())
+            .flat_map(|scope_metrics| scope_metrics.metrics.iter())
+            .filter(|metric| metric.name == HTTP_SERVER_REQUEST_REJECTED)
+            .filter_map(|metric| metric.data.as_any().downcast_ref::<Sum<u64>>())
+            .flat_map(|sum| sum.data_points.iter())
+            .map(|data_point| data_point.value)
+            .sum();
+        (status, error_type, rejections)
+    }
+
+    #[tokio::test]
+    async fn test_body_under_limit() {
+        let (status, error_type, rejections) = call_echo("hello", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(error_type, None);
+        assert_eq!(rejections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_content_length_over_limit() {
+        let (status, error_type, rejections) = call_echo("hello world", false).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_type, Some(Value::from(PAYLOAD_TOO_LARGE)));
+        assert_eq!(rejections, 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_body_over_limit() {
+        let (status, error_type, rejections) = call_echo("hello world", true).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_type, Some(Value::from(PAYLOAD_TOO_LARGE)));
+        assert_eq!(rejections, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_route_rejection() {
+        let exporter = InMemoryMetricsExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(
+                PeriodicReader::builder(
+                    exporter.clone(),
+                    opentelemetry_sdk::runtime::TokioCurrentThread,
+                )
+                .build(),
+            )
+            .build();
+        let meter = Arc::new(meter_provider.meter("test"));
+        let app =
+            test::init_service(App::new().wrap(BodyLimit::new(meter, 8)).configure(route)).await;
+        let req = test::TestRequest::post()
+            .uri("/wp-login.php")
+            .insert_header((CONTENT_LENGTH, 11))
+            .set_payload("hello world")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+        let routes = exporter
+            .get_finished_metrics()
+            .unwrap()
+            .iter()
+            .flat_map(|resource_metrics| resource_metrics.scope_metrics.iter())
+            .flat_map(|scope_metrics| scope_metrics.metrics.iter())
+            .filter(|metric| metric.name == HTTP_SERVER_REQUEST_REJECTED)
+            .filter_map(|metric| metric.data.as_any().downcast_ref::<Sum<u64>>())
+            .flat_map(|sum| sum.data_points.iter())
+            .flat_map(|data_point| data_point.attributes.iter())
+            .filter(|kv| kv.key.as_str() == HTTP_ROUTE)
+            .map(|kv| kv.value.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(routes, [Value::from(UNMATCHED_ROUTE)]);
+    }
+}
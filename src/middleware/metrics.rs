@@ -1,43 +1,72 @@
-use actix_web::body::{BodySize, MessageBody};
+use crate::metrics::{LatencyWindow, TenantMeters};
+use crate::middleware::tracing::TraceInfo;
+use crate::middleware::{
+    bounded_content_type, bounded_route, declared_body_size, request_content_type, request_method,
+    request_scheme, HTTP_REQUEST_HEADER_CONTENT_TYPE,
+};
+use crate::telemetry::AttributeRenames;
+use crate::{DurationUnit, MetricsConfig};
+use actix_web::body::{BodySize, BoxBody, MessageBody};
 use actix_web::dev::{self, ServiceRequest, ServiceResponse};
-use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::http::header::CONTENT_ENCODING;
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpMessage};
 use futures_util::future;
 use futures_util::future::LocalBoxFuture;
 use opentelemetry::metrics::{Histogram, Meter, UpDownCounter};
-use opentelemetry::KeyValue;
+use opentelemetry::{Key, KeyValue};
+use opentelemetry_sdk::Resource;
 use opentelemetry_semantic_conventions::trace::{
     HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE, URL_SCHEME,
 };
+use std::error::Error as StdError;
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 const HTTP_SERVER_DURATION: &str = "http.server.duration";
 const HTTP_SERVER_ACTIVE_REQUESTS: &str = "http.server.active_requests";
 const HTTP_SERVER_REQUEST_SIZE: &str = "http.server.request.size";
 const HTTP_SERVER_RESPONSE_SIZE: &str = "http.server.response.size";
+const HTTP_SERVER_REQUEST_QUEUE_TIME: &str = "http.server.request.queue_time";
+const HTTP_SERVER_RESPONSE_TIME_TO_FIRST_BYTE: &str = "http.server.response.time_to_first_byte";
+const HTTP_SERVER_RESPONSE_COMPRESSION_RATIO: &str = "http.server.response.compression_ratio";
+const DURATION_MS: &str = "duration_ms";
+const TRACE_ID: &str = "trace_id";
 
 #[derive(Clone, Debug)]
 pub struct Metrics {
     http_server_duration: Histogram<f64>,
-    http_server_active_requests: UpDownCounter<i64>,
+    http_server_active_requests: Option<UpDownCounter<i64>>,
     http_server_request_size: Histogram<u64>,
     http_server_response_size: Histogram<u64>,
+    http_server_request_queue_time: Histogram<f64>,
+    http_server_response_time_to_first_byte: Histogram<f64>,
+    http_server_response_compression_ratio: Histogram<f64>,
+    duration_unit: DurationUnit,
 }
 
 impl Metrics {
-    fn new(meter: Arc<Meter>) -> Self {
+    fn new(meter: Arc<Meter>, config: &MetricsConfig) -> Self {
         let http_server_duration = meter
             .f64_histogram(HTTP_SERVER_DURATION)
             .with_description("Measures the duration of inbound HTTP requests.")
-            .with_unit("s")
+            .with_unit(config.duration_unit.as_str())
             .init();
 
-        let http_server_active_requests = meter
-            .i64_up_down_counter(HTTP_SERVER_ACTIVE_REQUESTS)
-            .with_description(
-                "Measures the number of concurrent HTTP requests that are currently in-flight.",
-            )
-            .init();
+        let http_server_active_requests = config.active_requests().then(|| {
+            meter
+                .i64_up_down_counter(HTTP_SERVER_ACTIVE_REQUESTS)
+                .with_description(
+                    "Measures the number of concurrent HTTP requests that are currently in-flight.",
+                )
+                .init()
+        });
 
         let http_server_request_size = meter
             .u64_histogram(HTTP_SERVER_REQUEST_SIZE)
@@ -51,24 +80,173 @@ impl Metrics {
             .with_unit("By")
             .init();
 
+        // actix doesn't expose when a request arrived on the socket, so this
+        // measures the time from this middleware receiving the request until its
+        // future is first polled by the worker. Time spent in the accept queue
+        // and parsing headers is not included.
+        let http_server_request_queue_time = meter
+            .f64_histogram(HTTP_SERVER_REQUEST_QUEUE_TIME)
+            .with_description(
+                "Measures the time inbound HTTP requests wait before being processed.",
+            )
+            .with_unit(config.duration_unit.as_str())
+            .init();
+
+        let http_server_response_time_to_first_byte = meter
+            .f64_histogram(HTTP_SERVER_RESPONSE_TIME_TO_FIRST_BYTE)
+            .with_description(
+                "Measures the time from receiving a request until the first response body chunk is ready.",
+            )
+            .with_unit(config.duration_unit.as_str())
+            .init();
+
+        let http_server_response_compression_ratio = meter
+            .f64_histogram(HTTP_SERVER_RESPONSE_COMPRESSION_RATIO)
+            .with_description(
+                "Measures the uncompressed size of encoded HTTP responses divided by their size on the wire.",
+            )
+            .with_unit("1")
+            .init();
+
         Metrics {
             http_server_active_requests,
             http_server_duration,
             http_server_request_size,
             http_server_response_size,
+            http_server_request_queue_time,
+            http_server_response_time_to_first_byte,
+            http_server_response_compression_ratio,
+            duration_unit: config.duration_unit,
         }
     }
 }
 
+/// The tenant a request belongs to, inserted into the request extensions by a
+/// middleware wrapped outside [HttpMetrics]. With
+/// [HttpMetrics::with_tenant_meters], the request's HTTP metrics are recorded
+/// with that tenant's meter.
+#[derive(Clone, Debug)]
+pub struct Tenant(pub String);
+
+/// Time source for request durations, replaceable in tests.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HttpMetrics {
     meter: Arc<Meter>,
+    config: MetricsConfig,
+    latency_window: Option<Arc<LatencyWindow>>,
+    clock: Arc<dyn Clock>,
+    resource_attributes: Vec<KeyValue>,
+    tenant_meters: Option<Arc<TenantMeters>>,
+    attribute_renames: AttributeRenames,
 }
 
 impl HttpMetrics {
-    pub fn new(meter: Arc<Meter>) -> Self {
-        Self { meter }
+    pub fn new(meter: Arc<Meter>, config: MetricsConfig) -> Self {
+        Self {
+            meter,
+            config,
+            latency_window: None,
+            clock: Arc::new(SystemClock),
+            resource_attributes: Vec::new(),
+            tenant_meters: None,
+            attribute_renames: AttributeRenames::default(),
+        }
     }
+
+    /// Records the attributes of `resource` listed in
+    /// `promote_resource_attributes` on every data point.
+    pub fn with_resource(mut self, resource: &Resource) -> Self {
+        self.resource_attributes = self
+            .config
+            .promote_resource_attributes
+            .iter()
+            .filter_map(|key| {
+                let key = Key::from(key.clone());
+                resource
+                    .get(key.clone())
+                    .map(|value| KeyValue::new(key, value))
+            })
+            .collect();
+        self
+    }
+
+    /// Records attributes under the names in `attribute_renames`, as spans
+    /// are exported.
+    pub fn with_attribute_renames(mut self, attribute_renames: AttributeRenames) -> Self {
+        self.attribute_renames = attribute_renames;
+        self
+    }
+
+    /// Records requests carrying a [Tenant] with that tenant's meter from
+    /// `tenant_meters`; others, and tenants past its limit, keep the default
+    /// meter.
+    pub fn with_tenant_meters(mut self, tenant_meters: Arc<TenantMeters>) -> Self {
+        self.tenant_meters = Some(tenant_meters);
+        self
+    }
+
+    /// Also records each request duration into `latency_window`, which
+    /// backs `GET /admin/latency`.
+    pub fn with_latency_window(mut self, latency_window: Arc<LatencyWindow>) -> Self {
+        self.latency_window = Some(latency_window);
+        self
+    }
+
+    /// Measures queue time and duration with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Creates the HTTP instruments up front and records a zero on
+    /// `http.server.active_requests` for `GET` over `http`, the series most
+    /// requests land on, so it is exported before the first request.
+    /// Histograms are only exported once something is recorded.
+    pub fn register_instruments(&self) {
+        let metrics = Metrics::new(self.meter.clone(), &self.config);
+        if let Some(active_requests) = &metrics.http_server_active_requests {
+            active_requests.add(
+                0,
+                &request_attributes(
+                    Method::GET.as_str(),
+                    "http",
+                    &self.resource_attributes,
+                    &self.attribute_renames,
+                ),
+            );
+        }
+    }
+}
+
+/// The attributes every data point of a request starts with.
+fn request_attributes(
+    method: &str,
+    scheme: &'static str,
+    resource_attributes: &[KeyValue],
+    renames: &AttributeRenames,
+) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new(renames.key(HTTP_REQUEST_METHOD), method.to_string()),
+        KeyValue::new(renames.key(URL_SCHEME), scheme),
+    ];
+    attributes.extend(resource_attributes.iter().map(|attribute| {
+        KeyValue::new(renames.key(attribute.key.clone()), attribute.value.clone())
+    }));
+    attributes
 }
 
 impl<S, B> dev::Transform<S, dev::ServiceRequest> for HttpMetrics
@@ -81,7 +259,7 @@ where
     S::Future: 'static,
     B: MessageBody + 'static,
 {
-    type Response = dev::ServiceResponse<B>;
+    type Response = dev::ServiceResponse<TimedBody>;
     type Error = actix_web::Error;
     type Transform = HttpMetricsMiddleware<S>;
     type InitError = ();
@@ -90,7 +268,13 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         let service = HttpMetricsMiddleware {
             service,
-            meter: self.meter.clone(),
+            metrics: Metrics::new(self.meter.clone(), &self.config),
+            config: self.config.clone(),
+            latency_window: self.latency_window.clone(),
+            clock: self.clock.clone(),
+            resource_attributes: self.resource_attributes.clone(),
+            tenant_meters: self.tenant_meters.clone(),
+            attribute_renames: self.attribute_renames.clone(),
         };
 
         future::ok(service)
@@ -99,7 +283,13 @@ where
 
 pub struct HttpMetricsMiddleware<S> {
     service: S,
-    meter: Arc<Meter>,
+    metrics: Metrics,
+    config: MetricsConfig,
+    latency_window: Option<Arc<LatencyWindow>>,
+    clock: Arc<dyn Clock>,
+    resource_attributes: Vec<KeyValue>,
+    tenant_meters: Option<Arc<TenantMeters>>,
+    attribute_renames: AttributeRenames,
 }
 impl<S, B> dev::Service<dev::ServiceRequest> for HttpMetricsMiddleware<S>
 where
@@ -111,40 +301,52 @@ where
     S::Future: 'static,
     B: MessageBody + 'static,
 {
-    type Response = dev::ServiceResponse<B>;
+    type Response = dev::ServiceResponse<TimedBody>;
     type Error = actix_web::Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     dev::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let metrics = Metrics::new(self.meter.clone());
-        let timer = SystemTime::now();
-        let mut attributes = Vec::new();
-        let request_method = req.method();
-
-        attributes.push(KeyValue::new(
-            HTTP_REQUEST_METHOD,
-            request_method.to_string(),
-        ));
-        attributes.push(KeyValue::new(
-            URL_SCHEME,
-            req.connection_info().scheme().to_string(),
-        ));
+        let metrics = self
+            .tenant_meters
+            .as_ref()
+            .and_then(|tenant_meters| {
+                let extensions = req.extensions();
+                let Tenant(tenant) = extensions.get::<Tenant>()?;
+                tenant_meters.http_metrics(tenant, |meter| Metrics::new(meter, &self.config))
+            })
+            .unwrap_or_else(|| self.metrics.clone());
+        let latency_window = self.latency_window.clone();
+        let clock = self.clock.clone();
+        let start = clock.now();
+        let renames = self.attribute_renames.clone();
+        let (request_method, _) = request_method(req.method());
+        let mut attributes = request_attributes(
+            request_method,
+            request_scheme(&req, self.config.trust_forwarded_proto),
+            &self.resource_attributes,
+            &renames,
+        );
 
-        metrics
-            .http_server_active_requests
-            .add(1, attributes.as_slice());
-        attributes.push(KeyValue::new(
-            HTTP_ROUTE,
-            req.match_pattern().unwrap_or_default(),
-        ));
-
-        let request_size = req
-            .headers()
-            .get(CONTENT_LENGTH)
-            .and_then(|len| len.to_str().ok().and_then(|s| s.parse().ok()))
-            .unwrap_or(0);
+        if let Some(active_requests) = &metrics.http_server_active_requests {
+            active_requests.add(1, &attributes);
+        }
+        // Decremented on the same series, without the attributes added below.
+        let active_request_attributes = attributes.clone();
+        let route = bounded_route(&req);
+        attributes.push(KeyValue::new(renames.key(HTTP_ROUTE), route.clone()));
+        if self.config.content_type_attribute {
+            if let Some(content_type) = request_content_type(req.headers()) {
+                attributes.push(KeyValue::new(
+                    renames.key(HTTP_REQUEST_HEADER_CONTENT_TYPE),
+                    bounded_content_type(&content_type).to_string(),
+                ));
+            }
+        }
+        let slow_request_threshold = self.config.slow_request_threshold();
+
+        let request_size = declared_body_size(req.headers()).unwrap_or(0);
         metrics
             .http_server_request_size
             .record(request_size, &attributes);
@@ -152,50 +354,296 @@ where
         let fut = self.service.call(req);
 
         Box::pin(async move {
-            let res = fut.await?;
-            let (req, res) = res.into_parts();
-            metrics.http_server_active_requests.add(-1, &attributes);
-
-            attributes.push(KeyValue::new(
-                HTTP_RESPONSE_STATUS_CODE,
-                res.status().as_u16() as i64,
-            ));
-
+            let queue_time = metrics
+                .duration_unit
+                .convert(clock.now().saturating_duration_since(start));
             metrics
-                .http_server_request_size
-                .record(request_size, &attributes);
+                .http_server_request_queue_time
+                .record(queue_time, &attributes);
 
-            let response_size = match res.body().size() {
-                BodySize::Sized(size) => size,
-                _ => 0,
-            };
-            metrics
-                .http_server_response_size
-                .record(response_size, &attributes);
+            let res = fut.await;
+            // Decremented for errors too, e.g. from RequestTimeout.
+            if let Some(active_requests) = &metrics.http_server_active_requests {
+                active_requests.add(-1, &active_request_attributes);
+            }
+            let timing = |status: StatusCode,
+                          body_size: BodySize,
+                          trace_id: String,
+                          uncompressed_size: Option<UncompressedSize>| {
+                attributes.push(KeyValue::new(
+                    renames.key(HTTP_RESPONSE_STATUS_CODE),
+                    status.as_u16() as i64,
+                ));
 
-            let elapsed = timer.elapsed().map(|t| t.as_secs_f64()).unwrap_or_default();
-            metrics.http_server_duration.record(elapsed, &attributes);
+                metrics
+                    .http_server_request_size
+                    .record(request_size, &attributes);
 
+                let response_size = match body_size {
+                    BodySize::Sized(size) => size,
+                    _ => 0,
+                };
+                metrics
+                    .http_server_response_size
+                    .record(response_size, &attributes);
+
+                let slow_request = slow_request_threshold.map(|threshold| SlowRequest {
+                    threshold,
+                    route,
+                    trace_id,
+                });
+                ResponseTiming {
+                    metrics,
+                    attributes,
+                    clock,
+                    start,
+                    latency_window,
+                    first_byte_recorded: false,
+                    uncompressed_size,
+                    sent_size: 0,
+                    slow_request,
+                }
+            };
+            let (req, res) = match res {
+                Ok(res) => res.into_parts(),
+                Err(err) => {
+                    // actix renders errors, e.g. RequestTimeout's 504, once
+                    // every middleware has returned, so record the response
+                    // it will send.
+                    let res = err.error_response();
+                    timing(res.status(), res.body().size(), String::new(), None).finish();
+                    return Err(err);
+                }
+            };
+            let trace_id = req
+                .extensions()
+                .get::<TraceInfo>()
+                .map(|trace_info| trace_info.trace_id.to_string())
+                .unwrap_or_default();
+            let uncompressed_size = res
+                .extensions()
+                .get::<UncompressedSize>()
+                .filter(|_| res.headers().contains_key(CONTENT_ENCODING))
+                .cloned();
+            let timing = timing(res.status(), res.body().size(), trace_id, uncompressed_size);
+            let res = res.map_body(|_, body| TimedBody {
+                inner: body.boxed(),
+                timing: Some(timing),
+            });
             Ok(ServiceResponse::new(req, res))
         })
     }
 }
 
+/// Measurements taken while the response body is sent.
+struct ResponseTiming {
+    metrics: Metrics,
+    attributes: Vec<KeyValue>,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    latency_window: Option<Arc<LatencyWindow>>,
+    first_byte_recorded: bool,
+    /// Present only for encoded responses counted by [count_uncompressed].
+    uncompressed_size: Option<UncompressedSize>,
+    sent_size: u64,
+    slow_request: Option<SlowRequest>,
+}
+
+/// What the `slow_request` warning reports, taken while the request is
+/// still around.
+struct SlowRequest {
+    threshold: Duration,
+    route: String,
+    /// Empty unless `record_trace` runs inside [HttpMetrics] and the request
+    /// did not fail with an error.
+    trace_id: String,
+}
+
+impl ResponseTiming {
+    fn record_first_byte(&mut self) {
+        if !self.first_byte_recorded {
+            self.first_byte_recorded = true;
+            let elapsed = self.clock.now().saturating_duration_since(self.start);
+            self.metrics.http_server_response_time_to_first_byte.record(
+                self.metrics.duration_unit.convert(elapsed),
+                &self.attributes,
+            );
+        }
+    }
+
+    fn finish(mut self) {
+        // Bodies the server never polls, e.g. empty ones, count as sent
+        // all at once.
+        self.record_first_byte();
+        let elapsed = self.clock.now().saturating_duration_since(self.start);
+        self.metrics.http_server_duration.record(
+            self.metrics.duration_unit.convert(elapsed),
+            &self.attributes,
+        );
+        if let Some(latency_window) = &self.latency_window {
+            latency_window.record(elapsed);
+        }
+        if let Some(slow_request) = self.slow_request.take() {
+            if elapsed > slow_request.threshold {
+                tracing::event!(
+                    target: "slow_request",
+                    tracing::Level::WARN,
+                    { HTTP_ROUTE } = slow_request.route,
+                    { DURATION_MS } = elapsed.as_secs_f64() * 1000.0,
+                    { TRACE_ID } = slow_request.trace_id,
+                    "slow request"
+                );
+            }
+        }
+        if let Some(uncompressed_size) = &self.uncompressed_size {
+            let uncompressed_size = uncompressed_size.0.load(Ordering::Relaxed);
+            // Nothing to compare for empty bodies.
+            if uncompressed_size > 0 && self.sent_size > 0 {
+                self.metrics.http_server_response_compression_ratio.record(
+                    uncompressed_size as f64 / self.sent_size as f64,
+                    &self.attributes,
+                );
+            }
+        }
+    }
+}
+
+/// The response body, boxed so it can be polled without pin projection.
+/// Records time to first byte on the first chunk and the request duration once
+/// the body is finished or dropped, so streamed responses count their whole
+/// transfer time.
+pub struct TimedBody {
+    inner: BoxBody,
+    timing: Option<ResponseTiming>,
+}
+
+impl MessageBody for TimedBody {
+    type Error = Box<dyn StdError>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(chunk)) => {
+                if let Some(timing) = &mut this.timing {
+                    timing.record_first_byte();
+                    if let Ok(chunk) = chunk {
+                        timing.sent_size += chunk.len() as u64;
+                    }
+                }
+            }
+            Poll::Ready(None) => {
+                if let Some(timing) = this.timing.take() {
+                    timing.finish();
+                }
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+impl Drop for TimedBody {
+    fn drop(&mut self) {
+        if let Some(timing) = self.timing.take() {
+            timing.finish();
+        }
+    }
+}
+
+/// Response bytes produced before compression, shared between
+/// [count_uncompressed] and [HttpMetrics] through the response extensions.
+#[derive(Clone, Debug, Default)]
+pub struct UncompressedSize(Arc<AtomicU64>);
+
+/// Counts response bytes before `Compress` encodes them, so [HttpMetrics] can
+/// record `http.server.response.compression_ratio`. Wrap it inside `Compress`
+/// and `HttpMetrics` outside; responses sent without a `Content-Encoding`
+/// record no ratio.
+pub async fn count_uncompressed(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut res = next.call(req).await?;
+    let size = UncompressedSize::default();
+    res.response_mut().extensions_mut().insert(size.clone());
+    Ok(res.map_body(|_, body| CountedBody {
+        inner: body.boxed(),
+        size,
+    }))
+}
+
+/// A response body adding the length of every chunk to an [UncompressedSize].
+struct CountedBody {
+    inner: BoxBody,
+    size: UncompressedSize,
+}
+
+impl MessageBody for CountedBody {
+    type Error = Box<dyn StdError>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            this.size.0.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::route;
+    use crate::api::{route, OPS_COUNT};
     use crate::middleware::tracing::record_trace;
-    use crate::AppContext;
-    use actix_web::middleware::from_fn;
-    use actix_web::{test, web, App};
+    use crate::middleware::UNMATCHED_ROUTE;
+    use crate::testing::TestTelemetry;
+    use crate::{AppContext, RandomConfig};
+    use actix_web::middleware::{from_fn, Compress};
+    use actix_web::{test, web, App, HttpResponse};
+    use futures_util::{stream, StreamExt};
+    use opentelemetry::logs::{AnyValue, Severity};
     use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::metrics::data::{Metric, ResourceMetrics};
     use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
     use opentelemetry_sdk::testing::metrics::InMemoryMetricsExporter;
-    use std::sync::Arc;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
-    #[tokio::test]
-    async fn test_http_metrics() {
+    /// A clock that only moves when advanced.
+    #[derive(Debug)]
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn init_test_meter() -> (InMemoryMetricsExporter, SdkMeterProvider, Arc<Meter>) {
         let exporter = InMemoryMetricsExporter::default();
         let meter_provider = SdkMeterProvider::builder()
             .with_reader(
@@ -207,17 +655,33 @@ mod tests {
             )
             .build();
         let meter = Arc::new(meter_provider.meter("test"));
+        (exporter, meter_provider, meter)
+    }
+
+    fn find_metric<'a>(finished_metrics: &'a [ResourceMetrics], name: &str) -> &'a Metric {
+        finished_metrics
+            .iter()
+            .flat_map(|resource_metrics| resource_metrics.scope_metrics.iter())
+            .flat_map(|scope_metrics| scope_metrics.metrics.iter())
+            .find(|metric| metric.name == name)
+            .unwrap_or_else(|| panic!("metric {name} not found"))
+    }
+
+    #[tokio::test]
+    async fn test_http_metrics() {
+        let (exporter, meter_provider, meter) = init_test_meter();
         let app = test::init_service(
             App::new()
-                .app_data(web::Data::new(AppContext::new(meter.clone())))
+                .app_data(web::Data::new(AppContext::new(meter_provider.clone())))
                 .wrap(from_fn(record_trace))
-                .wrap(HttpMetrics::new(meter.clone()))
+                .wrap(HttpMetrics::new(meter.clone(), MetricsConfig::default()))
                 .configure(route),
         )
         .await;
         let req = test::TestRequest::get().uri("/").to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), 200);
+        drop(resp);
 
         meter_provider.force_flush().unwrap();
 
@@ -236,5 +700,833 @@ mod tests {
         assert!(finished_metrics_name.contains(&HTTP_SERVER_DURATION));
         assert!(finished_metrics_name.contains(&HTTP_SERVER_REQUEST_SIZE));
         assert!(finished_metrics_name.contains(&HTTP_SERVER_RESPONSE_SIZE));
+        assert!(finished_metrics_name.contains(&HTTP_SERVER_REQUEST_QUEUE_TIME));
+        assert!(finished_metrics_name.contains(&HTTP_SERVER_RESPONSE_TIME_TO_FIRST_BYTE));
+    }
+
+    #[tokio::test]
+    async fn test_active_requests_disabled() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let config = MetricsConfig {
+            active_requests: Some(false),
+            ..MetricsConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppContext::new(meter_provider.clone())))
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter.clone(), config))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let names = finished_metrics
+            .iter()
+            .flat_map(|resource_metrics| resource_metrics.scope_metrics.iter())
+            .flat_map(|scope_metrics| scope_metrics.metrics.iter())
+            .map(|metric| metric.name.as_ref())
+            .collect::<Vec<_>>();
+        assert!(!names.contains(&HTTP_SERVER_ACTIVE_REQUESTS));
+        assert!(names.contains(&HTTP_SERVER_DURATION));
+        assert!(names.contains(&HTTP_SERVER_RESPONSE_SIZE));
+    }
+
+    #[tokio::test]
+    async fn test_duration_unit_milliseconds() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let config = MetricsConfig {
+            duration_unit: DurationUnit::Milliseconds,
+            ..MetricsConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppContext::new(meter_provider.clone())))
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter.clone(), config))
+                .configure(route),
+        )
+        .await;
+        let timer = Instant::now();
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+        let upper_bound = timer.elapsed();
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        assert_eq!(duration.unit, "ms");
+
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let sum = histogram.data_points[0].sum;
+        assert!(sum > 0.0);
+        assert!(sum <= DurationUnit::Milliseconds.convert(upper_bound));
+    }
+
+    #[tokio::test]
+    async fn test_injected_clock_duration() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let config = MetricsConfig {
+            duration_unit: DurationUnit::Milliseconds,
+            ..MetricsConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(clock.clone()))
+                .wrap(HttpMetrics::new(meter.clone(), config).with_clock(clock.clone()))
+                .route(
+                    "/slow",
+                    web::get().to(|clock: web::Data<Arc<ManualClock>>| async move {
+                        clock.advance(Duration::from_millis(1500));
+                        "done"
+                    }),
+                ),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let sum_of = |name| {
+            find_metric(&finished_metrics, name)
+                .data
+                .as_any()
+                .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+                .unwrap()
+                .data_points[0]
+                .sum
+        };
+        assert_eq!(sum_of(HTTP_SERVER_DURATION), 1500.0);
+        assert_eq!(sum_of(HTTP_SERVER_REQUEST_QUEUE_TIME), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_time_to_first_byte() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let config = MetricsConfig {
+            duration_unit: DurationUnit::Milliseconds,
+            ..MetricsConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(clock.clone()))
+                .wrap(HttpMetrics::new(meter.clone(), config).with_clock(clock.clone()))
+                .route(
+                    "/stream",
+                    web::get().to(|clock: web::Data<Arc<ManualClock>>| async move {
+                        let chunks = stream::iter(["first", "second"]).map(move |chunk| {
+                            if chunk == "second" {
+                                clock.advance(Duration::from_millis(200));
+                            }
+                            Ok::<_, actix_web::Error>(Bytes::from(chunk))
+                        });
+                        HttpResponse::Ok().streaming(chunks)
+                    }),
+                ),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/stream").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, "firstsecond");
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let sum_of = |name| {
+            find_metric(&finished_metrics, name)
+                .data
+                .as_any()
+                .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+                .unwrap()
+                .data_points[0]
+                .sum
+        };
+        assert_eq!(sum_of(HTTP_SERVER_RESPONSE_TIME_TO_FIRST_BYTE), 0.0);
+        assert_eq!(sum_of(HTTP_SERVER_DURATION), 200.0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_warning() {
+        let telemetry = TestTelemetry::new();
+        let _guard = telemetry.set_default();
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let config = MetricsConfig {
+            slow_request_threshold_ms: Some(1000),
+            ..MetricsConfig::default()
+        };
+        let meter = Arc::new(telemetry.meter_provider().meter("test"));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(clock.clone()))
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter, config).with_clock(clock.clone()))
+                .route(
+                    "/slow",
+                    web::get().to(|clock: web::Data<Arc<ManualClock>>| async move {
+                        clock.advance(Duration::from_millis(1500));
+                        "done"
+                    }),
+                )
+                .route("/fast", web::get().to(|| async { "done" })),
+        )
+        .await;
+        for uri in ["/slow", "/fast"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+            drop(resp);
+        }
+
+        let collected = telemetry.collect();
+        let warnings = collected
+            .logs
+            .iter()
+            .filter(|log| log.record.severity_number == Some(Severity::Warn))
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        let attribute = |name: &str| {
+            warnings[0]
+                .record
+                .attributes_iter()
+                .find(|(key, _)| key.as_str() == name)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(attribute(HTTP_ROUTE), Some(AnyValue::from("/slow")));
+        assert_eq!(attribute(DURATION_MS), Some(AnyValue::from(1500.0)));
+        let request_span = collected
+            .spans
+            .iter()
+            .find(|span| span.name == "GET /slow")
+            .unwrap();
+        assert_eq!(
+            attribute(TRACE_ID),
+            Some(AnyValue::from(
+                request_span.span_context.trace_id().to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compression_ratio() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(count_uncompressed))
+                .wrap(Compress::default())
+                .wrap(HttpMetrics::new(meter.clone(), MetricsConfig::default()))
+                .route(
+                    "/text",
+                    web::get().to(|| async { "compressible ".repeat(1000) }),
+                ),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/text")
+            .insert_header(("accept-encoding", "gzip"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+        let compressed = test::read_body(resp).await;
+        // Identity responses have nothing to compare.
+        let req = test::TestRequest::get().uri("/text").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(test::read_body(resp).await.len(), 13000);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let ratio = find_metric(&finished_metrics, HTTP_SERVER_RESPONSE_COMPRESSION_RATIO);
+        assert_eq!(ratio.unit, "1");
+        let histogram = ratio
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        assert_eq!(histogram.data_points.len(), 1);
+        assert_eq!(histogram.data_points[0].count, 1);
+        assert_eq!(
+            histogram.data_points[0].sum,
+            13000.0 / compressed.len() as f64
+        );
+        assert!(histogram.data_points[0].sum > 10.0);
+    }
+
+    async fn assign_tenant(
+        req: ServiceRequest,
+        next: Next<impl MessageBody + 'static>,
+    ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        if let Some(tenant) = req.headers().get("x-tenant") {
+            let tenant = tenant.to_str().unwrap().to_string();
+            req.extensions_mut().insert(Tenant(tenant));
+        }
+        next.call(req).await
+    }
+
+    /// Requests per meter scope after one request for each of `tenants`.
+    async fn tenant_request_counts(
+        tenant_meters: impl FnOnce(SdkMeterProvider) -> TenantMeters,
+        tenants: &[&str],
+    ) -> HashMap<String, u64> {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let tenant_meters = Arc::new(tenant_meters(meter_provider.clone()));
+        let context =
+            AppContext::new(meter_provider.clone()).with_tenant_meters(tenant_meters.clone());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(from_fn(record_trace))
+                .wrap(
+                    HttpMetrics::new(meter.clone(), MetricsConfig::default())
+                        .with_tenant_meters(tenant_meters),
+                )
+                .wrap(from_fn(assign_tenant))
+                .configure(route),
+        )
+        .await;
+        for &tenant in tenants {
+            let req = test::TestRequest::get()
+                .uri("/")
+                .insert_header(("x-tenant", tenant))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+            drop(resp);
+        }
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        finished_metrics
+            .iter()
+            .flat_map(|resource_metrics| resource_metrics.scope_metrics.iter())
+            .filter_map(|scope_metrics| {
+                let duration = scope_metrics
+                    .metrics
+                    .iter()
+                    .find(|metric| metric.name == HTTP_SERVER_DURATION)?;
+                let histogram = duration
+                    .data
+                    .as_any()
+                    .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+                    .unwrap();
+                let count = histogram
+                    .data_points
+                    .iter()
+                    .map(|data_point| data_point.count)
+                    .sum::<u64>();
+                Some((scope_metrics.scope.name.to_string(), count))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_tenant_scoped_meters() {
+        let request_counts =
+            tenant_request_counts(TenantMeters::new, &["acme", "globex", "acme"]).await;
+        assert_eq!(
+            request_counts,
+            HashMap::from([
+                (format!("{}.tenant.acme", crate::METER_NAME), 2),
+                (format!("{}.tenant.globex", crate::METER_NAME), 1),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tenant_meters_fallback() {
+        let request_counts = tenant_request_counts(
+            |meter_provider| TenantMeters::new(meter_provider).with_max_tenants(1),
+            &["acme", "globex", "initech", "acme"],
+        )
+        .await;
+        assert_eq!(
+            request_counts,
+            HashMap::from([
+                (format!("{}.tenant.acme", crate::METER_NAME), 2),
+                ("test".to_string(), 2),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tenant_metrics_built_once() {
+        let tenant_meters = TenantMeters::new(SdkMeterProvider::default()).with_max_tenants(2);
+        let mut builds = 0;
+        for tenant in ["acme", "globex", "acme", "initech", "acme"] {
+            tenant_meters.http_metrics(tenant, |meter| {
+                builds += 1;
+                Metrics::new(meter, &MetricsConfig::default())
+            });
+        }
+        assert_eq!(builds, 2);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_proto_scheme() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let config = MetricsConfig {
+            trust_forwarded_proto: true,
+            ..MetricsConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(HttpMetrics::new(meter.clone(), config))
+                .route("/", web::get().to(|| async { "done" })),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("x-forwarded-proto", "https"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let scheme = histogram.data_points[0]
+            .attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == URL_SCHEME)
+            .map(|attribute| attribute.value.clone());
+        assert_eq!(scheme, Some(Value::from("https")));
+    }
+
+    #[tokio::test]
+    async fn test_register_instruments() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let renames = AttributeRenames::new(&BTreeMap::from([(
+            HTTP_REQUEST_METHOD.to_string(),
+            "http.method".to_string(),
+        )]));
+        let http_metrics =
+            HttpMetrics::new(meter, MetricsConfig::default()).with_attribute_renames(renames);
+        http_metrics.register_instruments();
+        let _context = AppContext::new(meter_provider.clone());
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let active_requests = find_metric(&finished_metrics, HTTP_SERVER_ACTIVE_REQUESTS)
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Sum<i64>>()
+            .unwrap();
+        assert_eq!(active_requests.data_points.len(), 1);
+        assert_eq!(active_requests.data_points[0].value, 0);
+        let ops_count = find_metric(&finished_metrics, OPS_COUNT.name())
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Sum<f64>>()
+            .unwrap();
+        assert_eq!(ops_count.data_points[0].value, 0.0);
+
+        // The zero lands on the series a GET records, so it adds none.
+        let app = test::init_service(
+            App::new()
+                .wrap(http_metrics)
+                .route("/", web::get().to(|| async { "done" })),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let active_requests = find_metric(&finished_metrics[1..], HTTP_SERVER_ACTIVE_REQUESTS)
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Sum<i64>>()
+            .unwrap();
+        assert_eq!(active_requests.data_points.len(), 1);
+        assert_eq!(active_requests.data_points[0].value, 0);
+    }
+
+    #[tokio::test]
+    async fn test_content_type_attribute() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let config = MetricsConfig {
+            content_type_attribute: true,
+            ..MetricsConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(HttpMetrics::new(meter.clone(), config))
+                .route("/", web::post().to(|| async { "done" })),
+        )
+        .await;
+        for content_type in [
+            "application/json; charset=utf-8",
+            "application/vnd.acme+json",
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .insert_header(("content-type", content_type))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let mut content_types = histogram
+            .data_points
+            .iter()
+            .filter_map(|data_point| {
+                data_point
+                    .attributes
+                    .iter()
+                    .find(|attribute| attribute.key.as_str() == HTTP_REQUEST_HEADER_CONTENT_TYPE)
+                    .map(|attribute| attribute.value.to_string())
+            })
+            .collect::<Vec<_>>();
+        content_types.sort();
+        assert_eq!(content_types, ["_OTHER", "application/json"]);
+    }
+
+    #[tokio::test]
+    async fn test_attribute_renames() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let renames = AttributeRenames::new(&BTreeMap::from([(
+            HTTP_REQUEST_METHOD.to_string(),
+            "http.method".to_string(),
+        )]));
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    HttpMetrics::new(meter.clone(), MetricsConfig::default())
+                        .with_attribute_renames(renames),
+                )
+                .route("/", web::get().to(|| async { "done" })),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let keys = histogram.data_points[0]
+            .attributes
+            .iter()
+            .map(|attribute| attribute.key.as_str())
+            .collect::<Vec<_>>();
+        assert!(keys.contains(&"http.method"));
+        assert!(!keys.contains(&HTTP_REQUEST_METHOD));
+    }
+
+    #[tokio::test]
+    async fn test_promoted_resource_attributes() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let config = MetricsConfig {
+            promote_resource_attributes: vec!["deployment.environment".to_string()],
+            ..MetricsConfig::default()
+        };
+        let resource = Resource::new([
+            KeyValue::new("service.name", "example"),
+            KeyValue::new("deployment.environment", "staging"),
+        ]);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppContext::new(meter_provider.clone())))
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter.clone(), config).with_resource(&resource))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let attribute = |key: &str| {
+            histogram.data_points[0]
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(
+            attribute("deployment.environment"),
+            Some(Value::from("staging"))
+        );
+        assert_eq!(attribute("service.name"), None);
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_routes_share_one_series() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppContext::new(meter_provider.clone())))
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter.clone(), MetricsConfig::default()))
+                .configure(route),
+        )
+        .await;
+        for uri in ["/wp-admin", "/.env", "/x/y/z", "/"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            drop(resp);
+        }
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let mut routes = histogram
+            .data_points
+            .iter()
+            .map(|data_point| {
+                let route = data_point
+                    .attributes
+                    .iter()
+                    .find(|attribute| attribute.key.as_str() == HTTP_ROUTE)
+                    .map(|attribute| attribute.value.to_string())
+                    .unwrap();
+                (route, data_point.count)
+            })
+            .collect::<Vec<_>>();
+        routes.sort();
+        assert_eq!(
+            routes,
+            [("/".to_string(), 1), (UNMATCHED_ROUTE.to_string(), 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registered_routes_keep_their_series() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let context = AppContext::new(meter_provider.clone()).with_random_config(RandomConfig {
+            min: 0,
+            max: 0,
+            unit: DurationUnit::Milliseconds,
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter.clone(), MetricsConfig::default()))
+                .service(web::scope("/v1").configure(route)),
+        )
+        .await;
+        // One request per handler in route().
+        let requests = [
+            test::TestRequest::get().uri("/v1/"),
+            test::TestRequest::get().uri("/v1/random"),
+            test::TestRequest::post()
+                .uri("/v1/echo")
+                .set_payload("hello"),
+            test::TestRequest::get().uri("/v1/error/500"),
+            test::TestRequest::post()
+                .uri("/v1/enqueue")
+                .set_json(serde_json::json!({"name": "job"})),
+            test::TestRequest::get().uri("/v1/admin/latency"),
+            test::TestRequest::post().uri("/v1/metrics"),
+        ];
+        for req in requests {
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_ne!(resp.status(), 404, "{:?}", resp.request().uri());
+            drop(resp);
+        }
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let mut routes = histogram
+            .data_points
+            .iter()
+            .map(|data_point| {
+                data_point
+                    .attributes
+                    .iter()
+                    .find(|attribute| attribute.key.as_str() == HTTP_ROUTE)
+                    .map(|attribute| attribute.value.to_string())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        routes.sort();
+        assert_eq!(
+            routes,
+            [
+                "/v1/",
+                "/v1/admin/latency",
+                "/v1/echo",
+                "/v1/enqueue",
+                "/v1/error/{code}",
+                "/v1/metrics",
+                "/v1/random",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scoped_route_template() {
+        let (exporter, meter_provider, meter) = init_test_meter();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppContext::new(meter_provider.clone())))
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter.clone(), MetricsConfig::default()))
+                .service(web::scope("/api/v1").configure(route)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/api/v1/error/503")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        drop(resp);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let duration = find_metric(&finished_metrics, HTTP_SERVER_DURATION);
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Histogram<f64>>()
+            .unwrap();
+        let route = histogram.data_points[0]
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == HTTP_ROUTE)
+            .map(|kv| kv.value.clone());
+        assert_eq!(route, Some(Value::from("/api/v1/error/{code}")));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_registry() {
+        let (exporter, meter_provider, _meter) = init_test_meter();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppContext::new(meter_provider.clone())))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/metrics").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let ops_count = find_metric(&finished_metrics, OPS_COUNT.name());
+        let sum = ops_count
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Sum<f64>>()
+            .unwrap();
+        assert_eq!(sum.data_points[0].value, 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_app_context_meter_provider() {
+        let (exporter, meter_provider, _meter) = init_test_meter();
+        let context = AppContext::new(meter_provider.clone());
+        context
+            .meter_provider()
+            .meter("custom")
+            .u64_counter("custom_count")
+            .init()
+            .add(1, &[]);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        find_metric(&finished_metrics, "custom_count");
+    }
+
+    #[tokio::test]
+    async fn test_app_context_observable_instruments() {
+        let (exporter, meter_provider, _meter) = init_test_meter();
+        let mut context = AppContext::new(meter_provider.clone());
+        context
+            .register_observable_gauge("queue_depth", || 7.0)
+            .register_observable_counter("jobs_processed", || 42);
+
+        meter_provider.force_flush().unwrap();
+
+        let finished_metrics = exporter.get_finished_metrics().unwrap();
+        let gauge = find_metric(&finished_metrics, "queue_depth")
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Gauge<f64>>()
+            .unwrap();
+        assert_eq!(gauge.data_points[0].value, 7.0);
+        let counter = find_metric(&finished_metrics, "jobs_processed")
+            .data
+            .as_any()
+            .downcast_ref::<opentelemetry_sdk::metrics::data::Sum<u64>>()
+            .unwrap();
+        assert_eq!(counter.data_points[0].value, 42);
     }
 }
@@ -1,2 +1,177 @@
+pub mod access_log;
+pub mod body_limit;
 pub mod metrics;
+pub mod timeout;
 pub mod tracing;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use actix_web::http::Method;
+
+/// The `http.request.header.content_type` attribute.
+pub const HTTP_REQUEST_HEADER_CONTENT_TYPE: &str = "http.request.header.content_type";
+
+/// The `http.request.method` value for `method`: its name if it's one of the
+/// methods known to the HTTP semantic conventions, `_OTHER` otherwise. The
+/// second value is the original method to record as
+/// `http.request.method_original` in the `_OTHER` case, so arbitrary methods
+/// can't blow up attribute cardinality.
+pub fn request_method(method: &Method) -> (&str, Option<&str>) {
+    const KNOWN: [Method; 9] = [
+        Method::CONNECT,
+        Method::DELETE,
+        Method::GET,
+        Method::HEAD,
+        Method::OPTIONS,
+        Method::PATCH,
+        Method::POST,
+        Method::PUT,
+        Method::TRACE,
+    ];
+    if KNOWN.contains(method) {
+        (method.as_str(), None)
+    } else {
+        ("_OTHER", Some(method.as_str()))
+    }
+}
+
+/// The scheme the client connected with, `http` or `https`.
+///
+/// Without `trust_forwarded_proto` this is the listener's scheme, so behind a
+/// TLS-terminating proxy it is always `http`. With it, `Forwarded: proto=` and
+/// `X-Forwarded-Proto` take precedence; only enable that when every request
+/// passes through a proxy that overwrites those headers. Forwarded values
+/// other than `http` and `https` are ignored, keeping the attribute bounded.
+pub fn request_scheme(req: &ServiceRequest, trust_forwarded_proto: bool) -> &'static str {
+    let forwarded = trust_forwarded_proto.then(|| {
+        match req.connection_info().scheme().to_ascii_lowercase().as_str() {
+            "https" => Some("https"),
+            "http" => Some("http"),
+            _ => None,
+        }
+    });
+    match forwarded.flatten() {
+        Some(scheme) => scheme,
+        None if req.app_config().secure() => "https",
+        None => "http",
+    }
+}
+
+/// The request's `Content-Type` without parameters such as `charset` or
+/// `boundary`, lowercased, e.g. `application/json` for
+/// `Application/JSON; charset=utf-8`.
+pub fn request_content_type(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let base = value.split(';').next().unwrap_or_default().trim();
+    (!base.is_empty()).then(|| base.to_ascii_lowercase())
+}
+
+/// `http.route` on metrics of requests that match no registered route.
+pub const UNMATCHED_ROUTE: &str = "__unmatched__";
+
+/// The `http.route` recorded on metrics. `match_pattern` is always a
+/// registered pattern, so unmatched requests for arbitrary paths all share
+/// the [UNMATCHED_ROUTE] series.
+pub fn bounded_route(req: &ServiceRequest) -> String {
+    req.match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string())
+}
+
+/// The body size the request's `Content-Length` declares, if it is valid.
+pub fn declared_body_size(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// `content_type` if it is one of a few common request body types,
+/// `_OTHER` otherwise, so clients can't blow up metric cardinality.
+pub fn bounded_content_type(content_type: &str) -> &str {
+    const KNOWN: [&str; 8] = [
+        "application/grpc",
+        "application/json",
+        "application/octet-stream",
+        "application/x-www-form-urlencoded",
+        "application/xml",
+        "multipart/form-data",
+        "text/html",
+        "text/plain",
+    ];
+    KNOWN
+        .into_iter()
+        .find(|known| *known == content_type)
+        .unwrap_or("_OTHER")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bounded_content_type, declared_body_size, request_content_type, request_method,
+        request_scheme,
+    };
+    use actix_web::http::header::HeaderMap;
+    use actix_web::http::Method;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_request_method() {
+        assert_eq!(request_method(&Method::GET), ("GET", None));
+        let custom = Method::from_bytes(b"PURGE").unwrap();
+        assert_eq!(request_method(&custom), ("_OTHER", Some("PURGE")));
+        // Methods are case-sensitive.
+        let lowercase = Method::from_bytes(b"get").unwrap();
+        assert_eq!(request_method(&lowercase), ("_OTHER", Some("get")));
+    }
+
+    #[test]
+    fn test_request_content_type() {
+        let content_type = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                actix_web::http::header::CONTENT_TYPE,
+                value.parse().unwrap(),
+            );
+            request_content_type(&headers)
+        };
+        assert_eq!(
+            content_type("Application/JSON; charset=utf-8").as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(
+            content_type("multipart/form-data; boundary=x").as_deref(),
+            Some("multipart/form-data")
+        );
+        assert_eq!(content_type(" ; charset=utf-8"), None);
+        assert_eq!(request_content_type(&HeaderMap::new()), None);
+
+        assert_eq!(bounded_content_type("application/json"), "application/json");
+        assert_eq!(bounded_content_type("application/vnd.acme+json"), "_OTHER");
+    }
+
+    #[test]
+    fn test_declared_body_size() {
+        let declared = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                actix_web::http::header::CONTENT_LENGTH,
+                value.parse().unwrap(),
+            );
+            declared_body_size(&headers)
+        };
+        assert_eq!(declared("42"), Some(42));
+        assert_eq!(declared("-1"), None);
+        assert_eq!(declared("ten"), None);
+        assert_eq!(declared_body_size(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_request_scheme() {
+        let forwarded = |proto| {
+            TestRequest::default()
+                .insert_header(("x-forwarded-proto", proto))
+                .to_srv_request()
+        };
+        assert_eq!(request_scheme(&forwarded("https"), true), "https");
+        assert_eq!(request_scheme(&forwarded("HTTPS"), true), "https");
+        assert_eq!(request_scheme(&forwarded("https"), false), "http");
+        assert_eq!(request_scheme(&forwarded("gopher"), true), "http");
+    }
+}
@@ -0,0 +1,219 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{self, ServiceRequest};
+use actix_web::error;
+use futures_util::future::{self, LocalBoxFuture};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const TIMED_OUT: &str = "request timed out";
+
+/// Fails requests that take longer than their route's timeout with a 504
+/// error, dropping the handler's future. The error is returned rather than a
+/// response, as the request is owned by the timed out future and can't be
+/// cloned before routing; `HttpMetrics` and `access_log` record it as the 504
+/// actix sends. Wrap this before `record_trace` so the span records it.
+///
+/// Routes are looked up by `match_pattern()`; other routes get `default`, or
+/// no timeout when it is `None`.
+#[derive(Clone, Debug)]
+pub struct RequestTimeout {
+    default: Option<Duration>,
+    routes: Arc<HashMap<String, Duration>>,
+}
+
+impl RequestTimeout {
+    pub fn new(default: Option<Duration>) -> Self {
+        Self {
+            default,
+            routes: Arc::default(),
+        }
+    }
+
+    /// Overrides the default for each route pattern, e.g. `/random`.
+    pub fn with_route_timeouts(
+        mut self,
+        routes: impl IntoIterator<Item = (String, Duration)>,
+    ) -> Self {
+        self.routes = Arc::new(routes.into_iter().collect());
+        self
+    }
+
+    fn timeout(&self, pattern: Option<&str>) -> Option<Duration> {
+        pattern
+            .and_then(|pattern| self.routes.get(pattern).copied())
+            .or(self.default)
+    }
+}
+
+impl<S, B> dev::Transform<S, ServiceRequest> for RequestTimeout
+where
+    S: dev::Service<ServiceRequest, Response = dev::ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = dev::ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestTimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(RequestTimeoutMiddleware {
+            service,
+            timeout: self.clone(),
+        })
+    }
+}
+
+pub struct RequestTimeoutMiddleware<S> {
+    service: S,
+    timeout: RequestTimeout,
+}
+
+impl<S, B> dev::Service<ServiceRequest> for RequestTimeoutMiddleware<S>
+where
+    S: dev::Service<ServiceRequest, Response = dev::ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = dev::ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let timeout = self.timeout.timeout(req.match_pattern().as_deref());
+        let fut = self.service.call(req);
+        let Some(timeout) = timeout else {
+            return Box::pin(fut);
+        };
+
+        Box::pin(async move {
+            tokio::time::timeout(timeout, fut)
+                .await
+                .unwrap_or_else(|_| Err(error::ErrorGatewayTimeout(TIMED_OUT)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::route;
+    use crate::middleware::metrics::HttpMetrics;
+    use crate::middleware::tracing::record_trace;
+    use crate::{AppContext, DurationUnit, MetricsConfig, RandomConfig};
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App};
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::metrics::data::Histogram;
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::testing::metrics::InMemoryMetricsExporter;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_semantic_conventions::trace::{EXCEPTION_MESSAGE, HTTP_RESPONSE_STATUS_CODE};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[tokio::test]
+    async fn test_route_timeout() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .set_default();
+
+        let metrics_exporter = InMemoryMetricsExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(
+                PeriodicReader::builder(
+                    metrics_exporter.clone(),
+                    opentelemetry_sdk::runtime::TokioCurrentThread,
+                )
+                .build(),
+            )
+            .build();
+        let meter = Arc::new(meter_provider.meter("test"));
+
+        // /random sleeps for at least 2s.
+        let context = AppContext::new(meter_provider.clone()).with_random_config(RandomConfig {
+            min: 2,
+            max: 5,
+            unit: DurationUnit::Seconds,
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(
+                    RequestTimeout::new(Some(Duration::from_secs(30)))
+                        .with_route_timeouts([("/random".to_string(), Duration::from_secs(1))]),
+                )
+                .wrap(from_fn(record_trace))
+                .wrap(HttpMetrics::new(meter, MetricsConfig::default()))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/random").to_request();
+        let Err(err) = test::try_call_service(&app, req).await else {
+            panic!("expected /random to time out");
+        };
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        drop(err);
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let exception_message = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap()
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == EXCEPTION_MESSAGE)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(
+            exception_message("GET /random"),
+            Some(Value::from(TIMED_OUT))
+        );
+        assert_eq!(exception_message("GET /"), None);
+
+        meter_provider.force_flush().unwrap();
+        let finished_metrics = metrics_exporter.get_finished_metrics().unwrap();
+        let histogram = finished_metrics
+            .iter()
+            .flat_map(|resource_metrics| resource_metrics.scope_metrics.iter())
+            .flat_map(|scope_metrics| scope_metrics.metrics.iter())
+            .find(|metric| metric.name == "http.server.duration")
+            .and_then(|metric| metric.data.as_any().downcast_ref::<Histogram<f64>>())
+            .unwrap();
+        let mut statuses = histogram
+            .data_points
+            .iter()
+            .map(|data_point| {
+                let status = data_point
+                    .attributes
+                    .iter()
+                    .find(|kv| kv.key.as_str() == HTTP_RESPONSE_STATUS_CODE)
+                    .map(|kv| kv.value.clone())
+                    .unwrap();
+                (status.to_string(), data_point.count)
+            })
+            .collect::<Vec<_>>();
+        statuses.sort();
+        assert_eq!(statuses, [("200".to_string(), 1), ("504".to_string(), 1)]);
+    }
+}
@@ -1,20 +1,109 @@
-use actix_web::body::MessageBody;
-use actix_web::dev::{ServiceRequest, ServiceResponse};
-use actix_web::http::header::{HeaderMap, HeaderName};
+use crate::middleware::{
+    declared_body_size, request_content_type, request_method, request_scheme,
+    HTTP_REQUEST_HEADER_CONTENT_TYPE,
+};
+use crate::telemetry::propagation::{extract_context, HeaderExtractor};
+use crate::{CaptureHeaders, SpanLevel, TracingConfig};
+use actix_web::body::{BodySize as ResponseBodySize, BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_ENCODING, COOKIE,
+    PROXY_AUTHORIZATION,
+};
+use actix_web::http::StatusCode;
 use actix_web::middleware::Next;
-use actix_web::{Error, HttpMessage, HttpRequest};
-use opentelemetry::propagation::{Extractor, TextMapPropagator};
-use opentelemetry::trace::{FutureExt, TraceContextExt, TraceId};
-use opentelemetry::Context;
+use actix_web::web::Bytes;
+use actix_web::{web, Error, HttpMessage};
+use futures_util::Stream;
+use opentelemetry::propagation::{TextMapCompositePropagator, TextMapPropagator};
+use opentelemetry::trace::{SpanContext, TraceContextExt, TraceId};
+use opentelemetry::KeyValue;
 use opentelemetry_sdk::propagation::TraceContextPropagator;
 use opentelemetry_semantic_conventions::trace::{
-    CLIENT_ADDRESS, ERROR_TYPE, EXCEPTION_ESCAPED, EXCEPTION_MESSAGE, EXCEPTION_STACKTRACE,
-    EXCEPTION_TYPE, HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE,
-    NETWORK_PROTOCOL_VERSION, URL_PATH, USER_AGENT_ORIGINAL,
+    CLIENT_ADDRESS, ERROR_TYPE, EXCEPTION_MESSAGE, HTTP_REQUEST_METHOD,
+    HTTP_REQUEST_METHOD_ORIGINAL, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE, NETWORK_PROTOCOL_VERSION,
+    RPC_GRPC_STATUS_CODE, URL_PATH, URL_SCHEME, USER_AGENT_ORIGINAL,
 };
-use std::any::Any;
-use tracing::{field, Span};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tracing::subscriber::Interest;
+use tracing::{field, Level, Metadata, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer;
+
+const HTTP_REQUEST_BODY_SIZE: &str = "http.request.body.size";
+const HTTP_REQUEST_BODY_LENGTH_MISMATCH: &str = "http.request.body.length_mismatch";
+const SAMPLING_DECISION: &str = "sampling.decision";
+const SAMPLING_RATIO: &str = "sampling.ratio";
+const TRACE_REMOTE_PARENT: &str = "trace.remote_parent";
+const TLS: &str = "tls";
+const ENDUSER_ID: &str = "enduser.id";
+const X_TRACE_ID: &str = "x-trace-id";
+const ACTIX_WORKER_INDEX: &str = "actix.worker.index";
+const FEATURE_FLAG: &str = "feature_flag";
+const TRUNCATED_MARKER: &str = "...[truncated]";
+const HTTP_REQUEST_HEADER_ACCEPT: &str = "http.request.header.accept";
+const CACHE_HIT: &str = "cache.hit";
+const HTTP_ROUTE_PARAMS: &str = "http.route.params";
+
+/// Request headers recorded as their own `http.request.header.<name>`
+/// attribute. Keep this to headers with a handful of distinct values.
+const CAPTURED_REQUEST_HEADERS: [(HeaderName, &str); 1] = [(ACCEPT, HTTP_REQUEST_HEADER_ACCEPT)];
+
+const HTTP_RESPONSE_HEADER_CONTENT_ENCODING: &str = "http.response.header.content_encoding";
+
+/// Response headers recorded like [CAPTURED_REQUEST_HEADERS]. A missing header
+/// records nothing; e.g. no `Content-Encoding` means identity. Headers set by
+/// middleware only show up if it's wrapped before `record_trace`, as with
+/// `Compress`.
+const CAPTURED_RESPONSE_HEADERS: [(HeaderName, &str); 1] =
+    [(CONTENT_ENCODING, HTTP_RESPONSE_HEADER_CONTENT_ENCODING)];
+
+thread_local! {
+    /// Set while a request `record_trace` doesn't trace is polled.
+    static UNTRACED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Disables spans and events of requests `record_trace` doesn't trace, i.e.
+/// those sampled out by `route_sample_rates` or unmatched with
+/// `trace_only_matched_routes`. Without it their handler spans would be
+/// exported as new roots, so add it to every span exporting layer.
+#[derive(Clone, Copy, Debug)]
+pub struct TracedRequestsFilter;
+
+impl<S> layer::Filter<S> for TracedRequestsFilter {
+    fn enabled(&self, _metadata: &Metadata<'_>, _cx: &layer::Context<'_, S>) -> bool {
+        !UNTRACED.get()
+    }
+
+    /// The decision depends on the request being polled, so it can't be
+    /// cached per callsite.
+    fn callsite_enabled(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+}
+
+/// Polls a request future with [TracedRequestsFilter] disabling its spans.
+/// Boxed so it can be polled without pin projection.
+struct Untraced<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for Untraced<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let untraced = UNTRACED.replace(true);
+        let poll = self.0.as_mut().poll(cx);
+        UNTRACED.set(untraced);
+        poll
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct TraceInfo {
@@ -31,76 +120,657 @@ impl TraceInfo {
     }
 }
 
-struct HeaderExtractor<'a>(&'a HeaderMap);
+/// Attributes contributed by other middleware through request extensions.
+///
+/// Insert this into the request extensions (e.g. from an auth middleware) and
+/// `record_trace` copies every attribute onto the request span.
+#[derive(Clone, Debug, Default)]
+pub struct RequestContext {
+    pub attributes: Vec<KeyValue>,
+}
+
+impl RequestContext {
+    pub fn new(attributes: Vec<KeyValue>) -> Self {
+        Self { attributes }
+    }
+}
+
+/// Renames the request span, which defaults to `{method} {route}`.
+///
+/// Insert it into the request extensions from a handler, e.g. to name spans of
+/// a single RPC endpoint after the dispatched operation.
+#[derive(Clone, Debug)]
+pub struct SpanName(pub String);
+
+/// The authenticated user, inserted into the request extensions by an auth
+/// middleware. Recorded as `enduser.id` only when `record_enduser_id` is set.
+#[derive(Clone, Debug)]
+pub struct EndUserId(pub String);
+
+/// Feature flag variants assigned to the request, keyed by flag, inserted into
+/// the request extensions by an experiments middleware. Each assignment is
+/// recorded as `feature_flag.{key} = {variant}`.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFlags(pub BTreeMap<String, String>);
+
+/// Index of the actix worker serving the app, recorded as `actix.worker.index`.
+///
+/// Every worker builds its own `App`, so registering a distinct index as app
+/// data from the `HttpServer` factory tags each span with its worker. The
+/// span's `thread.name` identifies the worker thread too, but not as a number.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerIndex(pub usize);
+
+/// Overrides the `error.type` recorded for a failed response, which defaults to
+/// the status code. Insert it into the response extensions; it marks the span
+/// as failed even for a 4xx when `treat_4xx_as_error` is off.
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorType(pub &'static str);
+
+/// Whether the response was served from a cache, recorded as `cache.hit`.
+/// Insert it into the response extensions from a handler or caching
+/// middleware; responses without it record nothing.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheHit(pub bool);
+
+/// Bytes read from the request body so far, shared with [CountingPayload].
+#[derive(Clone, Debug, Default)]
+struct BodySize {
+    bytes: Arc<AtomicU64>,
+    finished: Arc<AtomicBool>,
+}
+
+impl BodySize {
+    /// The number of bytes read, once the body has been consumed to the end
+    /// (or failed).
+    fn get(&self) -> Option<u64> {
+        self.finished
+            .load(Ordering::Relaxed)
+            .then(|| self.bytes.load(Ordering::Relaxed))
+    }
+}
+
+struct CountingPayload {
+    inner: Payload,
+    size: BodySize,
+}
+
+impl Stream for CountingPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(bytes))) => {
+                self.size
+                    .bytes
+                    .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+            }
+            Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
+                self.size.finished.store(true, Ordering::Relaxed);
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+/// The response body, boxed like the metrics middleware's. It keeps the request
+/// span open while the body is sent and emits `response.sent` once it has been.
+struct TracedBody {
+    inner: BoxBody,
+    span: Option<Span>,
+}
+
+impl TracedBody {
+    fn new(inner: impl MessageBody + 'static, span: Option<Span>) -> Self {
+        Self {
+            inner: inner.boxed(),
+            span,
+        }
+    }
+}
+
+impl MessageBody for TracedBody {
+    type Error = Box<dyn StdError>;
+
+    fn size(&self) -> ResponseBodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(None) = poll {
+            if let Some(span) = this.span.take() {
+                tracing::event!(parent: &span, Level::INFO, "response.sent");
+            }
+        }
+        poll
+    }
+}
+
+impl Drop for TracedBody {
+    fn drop(&mut self) {
+        // Empty bodies are never polled; they are sent with the head.
+        if let Some(span) = self.span.take() {
+            if matches!(
+                self.inner.size(),
+                ResponseBodySize::None | ResponseBodySize::Sized(0)
+            ) {
+                tracing::event!(parent: &span, Level::INFO, "response.sent");
+            }
+        }
+    }
+}
+
+fn count_payload(req: &mut ServiceRequest) -> BodySize {
+    let size = BodySize::default();
+    let payload = CountingPayload {
+        inner: req.take_payload(),
+        size: size.clone(),
+    };
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(payload),
+    });
+    size
+}
+
+/// Cuts `value` to at most `max_len` bytes without splitting a character.
+fn truncate(mut value: String, max_len: Option<usize>) -> String {
+    if let Some(max_len) = max_len.filter(|max_len| value.len() > *max_len) {
+        let end = (0..=max_len)
+            .rev()
+            .find(|end| value.is_char_boundary(*end))
+            .unwrap_or(0);
+        value.truncate(end);
+    }
+    value
+}
+
+/// `path` with every segment matched by a dynamic segment of `pattern`
+/// replaced by the pattern's segment, e.g. `/users/42` for `/users/{id}`
+/// becomes `/users/{id}` and `/v2` for `/v{version}` becomes `/v{version}`.
+/// Regex constraints are dropped from the names. Paths whose segments don't
+/// line up with the pattern's, as with tail matches, are replaced by the
+/// pattern itself.
+fn template_path(path: &str, pattern: &str) -> String {
+    let path_segments = path.split('/');
+    let pattern_segments = pattern.split('/');
+    if path_segments.clone().count() != pattern_segments.clone().count() {
+        return pattern.to_string();
+    }
+    path_segments
+        .zip(pattern_segments)
+        .map(|(segment, pattern)| {
+            if pattern.contains('{') {
+                strip_constraints(pattern)
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `pattern` with the regex constraint of each dynamic segment dropped, e.g.
+/// `/code/{code}` for `/code/{code:\d{3}}`.
+fn strip_constraints(pattern: &str) -> String {
+    let mut stripped = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let name_end = rest.find([':', '}']).unwrap_or(rest.len());
+        stripped.push('{');
+        stripped.push_str(&rest[..name_end]);
+        stripped.push('}');
+        rest = &rest[param_end(rest)..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// The length of a dynamic segment up to and including its closing brace,
+/// given what follows its opening brace. Constraints may contain braces of
+/// their own, e.g. `{id:\d{3}}`.
+fn param_end(rest: &str) -> usize {
+    let mut depth = 1;
+    rest.char_indices()
+        .find(|(_, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        })
+        .map_or(rest.len(), |(i, _)| i + 1)
+}
+
+/// Names of the dynamic segments of `pattern`, in order, e.g. `org,id` for
+/// `/org/{org}/user/{id}`. Regex constraints are dropped, as in
+/// [template_path].
+fn route_param_names(pattern: &str) -> String {
+    let mut names = Vec::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        let name_end = rest.find([':', '}']).unwrap_or(rest.len());
+        names.push(&rest[..name_end]);
+        rest = &rest[param_end(rest)..];
+    }
+    names.join(",")
+}
+
+/// Headers whose values are replaced by `[REDACTED]` in the
+/// `http.request.headers` dump.
+const SENSITIVE_HEADERS: [HeaderName; 3] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+
+fn redact_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in SENSITIVE_HEADERS {
+        if headers.contains_key(&name) {
+            headers.insert(name, HeaderValue::from_static("[REDACTED]"));
+        }
+    }
+    headers
+}
 
-impl<'a> opentelemetry::propagation::Extractor for HeaderExtractor<'a> {
-    fn get(&self, key: &str) -> Option<&str> {
-        self.0.get(key).and_then(|value| value.to_str().ok())
+/// Cuts a captured header dump to at most `max_len` bytes, marking the cut.
+fn truncate_headers(value: String, max_len: Option<usize>) -> String {
+    if max_len.is_some_and(|max_len| value.len() > max_len) {
+        let mut value = truncate(value, max_len);
+        value.push_str(TRUNCATED_MARKER);
+        value
+    } else {
+        value
     }
+}
 
-    fn keys(&self) -> Vec<&str> {
-        self.0.keys().map(HeaderName::as_str).collect::<Vec<_>>()
+fn record_headers(
+    span: &Span,
+    headers: &HeaderMap,
+    captured: &[(HeaderName, &'static str)],
+    max_value_length: Option<usize>,
+) {
+    for (header, attribute) in captured {
+        if let Some(value) = headers.get(header) {
+            span.record(
+                *attribute,
+                truncate(
+                    value.to_str().unwrap_or_default().to_string(),
+                    max_value_length,
+                ),
+            );
+        }
     }
 }
 
+/// Parses comma-separated `traceparent` values into the span contexts to link.
+fn extract_links(value: &str) -> Vec<SpanContext> {
+    let propagator = TraceContextPropagator::new();
+    value
+        .split(',')
+        .filter_map(|traceparent| {
+            let carrier =
+                HashMap::from([("traceparent".to_string(), traceparent.trim().to_string())]);
+            let span_context = propagator.extract(&carrier).span().span_context().clone();
+            span_context.is_valid().then_some(span_context)
+        })
+        .collect()
+}
+
+/// Creates the request span at `$level`, which has to be a constant.
+macro_rules! request_span {
+    ($level:expr, $span_name:expr) => {
+        tracing::span!(
+            $level,
+            "",
+            otel.name = $span_name,
+            { URL_PATH } = field::Empty,
+            { HTTP_ROUTE } = field::Empty,
+            { HTTP_ROUTE_PARAMS } = field::Empty,
+            { HTTP_REQUEST_METHOD } = field::Empty,
+            { HTTP_REQUEST_METHOD_ORIGINAL } = field::Empty,
+            http.request.headers = field::Empty,
+            { HTTP_REQUEST_HEADER_ACCEPT } = field::Empty,
+            { HTTP_REQUEST_HEADER_CONTENT_TYPE } = field::Empty,
+            { HTTP_RESPONSE_HEADER_CONTENT_ENCODING } = field::Empty,
+            { HTTP_RESPONSE_STATUS_CODE } = field::Empty,
+            { NETWORK_PROTOCOL_VERSION } = field::Empty,
+            { URL_SCHEME } = field::Empty,
+            { TLS } = field::Empty,
+            { CLIENT_ADDRESS } = field::Empty,
+            { USER_AGENT_ORIGINAL } = field::Empty,
+            { HTTP_REQUEST_BODY_SIZE } = field::Empty,
+            { HTTP_REQUEST_BODY_LENGTH_MISMATCH } = field::Empty,
+            { ERROR_TYPE } = field::Empty,
+            { EXCEPTION_MESSAGE } = field::Empty,
+            otel.status_code = field::Empty,
+            otel.status_message = field::Empty,
+            { SAMPLING_DECISION } = field::Empty,
+            { SAMPLING_RATIO } = field::Empty,
+            { TRACE_REMOTE_PARENT } = field::Empty,
+            { ENDUSER_ID } = field::Empty,
+            { ACTIX_WORKER_INDEX } = field::Empty,
+            { RPC_GRPC_STATUS_CODE } = field::Empty,
+            { CACHE_HIT } = field::Empty,
+        )
+    };
+}
+
 fn make_span(req: &ServiceRequest) -> Span {
-    let empty = field::Empty;
-    let span_name = format!(
-        "{} {}",
-        req.method(),
-        req.match_pattern().unwrap_or_default()
-    );
-    let span = tracing::info_span!(
-        "",
-        otel.name = span_name,
-        { URL_PATH } = empty,
-        { HTTP_ROUTE } = empty,
-        { HTTP_REQUEST_METHOD } = empty,
-        http.request.headers = empty,
-        { HTTP_RESPONSE_STATUS_CODE } = empty,
-        { NETWORK_PROTOCOL_VERSION } = empty,
-        { CLIENT_ADDRESS } = empty,
-        { USER_AGENT_ORIGINAL } = empty,
-        { ERROR_TYPE } = empty,
+    // Non-standard methods would make span names unbounded, so they are named
+    // "HTTP {route}" as the semantic conventions suggest.
+    let method = match request_method(req.method()) {
+        (method, None) => method,
+        (_, Some(_)) => "HTTP",
+    };
+    let span_name = format!("{} {}", method, req.match_pattern().unwrap_or_default());
+    let config = req.app_data::<web::Data<TracingConfig>>();
+    let span = match config.map(|config| config.span_level).unwrap_or_default() {
+        SpanLevel::Trace => request_span!(Level::TRACE, span_name),
+        SpanLevel::Debug => request_span!(Level::DEBUG, span_name),
+        SpanLevel::Info => request_span!(Level::INFO, span_name),
+        SpanLevel::Warn => request_span!(Level::WARN, span_name),
+        SpanLevel::Error => request_span!(Level::ERROR, span_name),
+    };
+    // A propagator registered as app data overrides the global one.
+    let parent = match req.app_data::<web::Data<TextMapCompositePropagator>>() {
+        Some(propagator) => propagator.extract(&HeaderExtractor(req.headers())),
+        None => extract_context(req.headers()),
+    };
+    let remote_parent = parent.span().span_context().is_valid();
+    span.record(TRACE_REMOTE_PARENT, remote_parent);
+    span.set_parent(parent);
+
+    // Reading the context runs the sampler, so the decision is final here.
+    let sampled = span.context().span().span_context().is_sampled();
+    span.record(
+        SAMPLING_DECISION,
+        if sampled { "record_and_sample" } else { "drop" },
     );
-    span.set_parent(opentelemetry::global::get_text_map_propagator(
-        |propagator| propagator.extract(&HeaderExtractor(req.headers())),
-    ));
+    // The ratio only decides new traces; children follow their parent.
+    if !remote_parent {
+        span.record(
+            SAMPLING_RATIO,
+            config.map_or(1.0, |config| config.sampling_ratio()),
+        );
+    }
+
+    for (key, value) in config.iter().flat_map(|config| &config.span_attributes) {
+        span.set_attribute(key.clone(), value.clone());
+    }
+
+    let link_header = config.and_then(|config| config.link_header.as_deref());
+    if let Some(links) = link_header
+        .and_then(|header| req.headers().get(header))
+        .and_then(|value| value.to_str().ok())
+    {
+        for link in extract_links(links) {
+            span.add_link(link);
+        }
+    }
     span
 }
 
+/// Whether `status` fails a server span. Per the HTTP semantic conventions a
+/// 4xx is the client's fault and only counts when `treat_4xx_as_error` is set.
+fn is_error(status: StatusCode, treat_4xx_as_error: bool) -> bool {
+    if status.is_client_error() {
+        treat_4xx_as_error
+    } else {
+        !status.is_success()
+    }
+}
+
+/// Marks `span` as failed. `error.type` defaults to the status code and
+/// `exception.message` is recorded when a handler or middleware returned `err`.
+fn record_error(span: &Span, status: StatusCode, error_type: Option<&str>, err: Option<&Error>) {
+    match error_type {
+        Some(error_type) => span.record(ERROR_TYPE, error_type),
+        None => span.record(ERROR_TYPE, field::display(status)),
+    };
+    if let Some(err) = err {
+        span.record(EXCEPTION_MESSAGE, field::display(err));
+    }
+    span.record("otel.status_code", "ERROR");
+    span.record(
+        "otel.status_message",
+        field::display(format!("HTTP {}", status)),
+    );
+}
+
+/// Records the `grpc-status` of a proxied gRPC response; any status but OK
+/// (0) fails the span, even though the HTTP status is 200. actix can't send
+/// or read trailers, so only trailers-only responses, which carry the status
+/// as a header, are seen.
+fn record_grpc_status(span: &Span, headers: &HeaderMap) {
+    let Some(grpc_status) = headers
+        .get("grpc-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+    else {
+        return;
+    };
+    span.record(RPC_GRPC_STATUS_CODE, grpc_status);
+    if grpc_status != 0 {
+        span.record(ERROR_TYPE, field::display(grpc_status));
+        if let Some(message) = headers
+            .get("grpc-message")
+            .and_then(|value| value.to_str().ok())
+        {
+            span.record(EXCEPTION_MESSAGE, message);
+        }
+        span.record("otel.status_code", "ERROR");
+        span.record(
+            "otel.status_message",
+            field::display(format!("gRPC status {grpc_status}")),
+        );
+    }
+}
+
 pub async fn record_trace(
-    req: ServiceRequest,
-    next: Next<impl MessageBody>,
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
 ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let config = req.app_data::<web::Data<TracingConfig>>();
+    let pattern = req.match_pattern();
+    let sample_rate = config.and_then(|config| {
+        config
+            .route_sample_rates
+            .get(pattern.as_deref().unwrap_or_default())
+            .copied()
+    });
+    let unmatched =
+        pattern.is_none() && config.is_some_and(|config| config.trace_only_matched_routes);
+    if unmatched || sample_rate.is_some_and(|rate| rand::random::<f64>() >= rate) {
+        // Handlers still get a TraceInfo; TracedRequestsFilter keeps the
+        // spans they start from being exported as separate roots.
+        req.extensions_mut()
+            .insert(TraceInfo::new(TraceId::INVALID, Span::none()));
+        let res = Untraced(Box::pin(next.call(req))).await?;
+        return Ok(res.map_body(|_, body| TracedBody::new(body, None)));
+    }
+
     let span = make_span(&req);
-    let trace_info = TraceInfo::new(
-        span.context().span().span_context().trace_id(),
-        span.clone(),
+    tracing::event!(
+        parent: &span,
+        tracing::Level::INFO,
+        { HTTP_REQUEST_METHOD } = request_method(req.method()).0,
+        { HTTP_ROUTE } = req.match_pattern().unwrap_or_default(),
+        { CLIENT_ADDRESS } = req.connection_info().peer_addr(),
+        "request.received"
     );
-    req.extensions_mut().insert(trace_info);
-    let resp = next.call(req).await?;
-    let (req, res) = resp.into_parts();
+    let trace_id = span.context().span().span_context().trace_id();
+    req.extensions_mut()
+        .insert(TraceInfo::new(trace_id, span.clone()));
 
-    span.record(URL_PATH, req.path());
+    match &pattern {
+        Some(pattern) if config.is_some_and(|config| config.template_url_path) => {
+            span.record(URL_PATH, template_path(req.path(), pattern));
+        }
+        _ => {
+            span.record(URL_PATH, req.path());
+        }
+    }
     span.record(HTTP_ROUTE, req.match_pattern().unwrap_or_default());
-    span.record(HTTP_REQUEST_METHOD, req.method().as_str());
-    span.record("http.request.headers", field::debug(req.headers()));
-    span.record(NETWORK_PROTOCOL_VERSION, field::debug(req.version()));
-    span.record(
-        CLIENT_ADDRESS,
-        req.connection_info().peer_addr().unwrap_or_default(),
+    if let Some(params) = pattern
+        .as_deref()
+        .map(route_param_names)
+        .filter(|params| !params.is_empty())
+    {
+        span.record(HTTP_ROUTE_PARAMS, params);
+    }
+    let (method, method_original) = request_method(req.method());
+    span.record(HTTP_REQUEST_METHOD, method);
+    span.record(HTTP_REQUEST_METHOD_ORIGINAL, method_original);
+    let max_value_length = config.and_then(|config| config.max_attribute_value_length);
+    let scheme = request_scheme(
+        &req,
+        config.is_some_and(|config| config.trust_forwarded_proto),
+    );
+    let treat_4xx_as_error = config.is_some_and(|config| config.treat_4xx_as_error);
+    let record_enduser_id = config.is_some_and(|config| config.record_enduser_id);
+    let grpc = config.is_some_and(|config| config.grpc);
+    let expose_trace_id = config.is_some_and(|config| config.expose_trace_id);
+    let capture_headers = config
+        .map(|config| config.capture_headers)
+        .unwrap_or_default();
+    let max_header_capture_bytes = config.and_then(|config| config.max_header_capture_bytes);
+    let headers_dump = move |headers: &HeaderMap| {
+        let headers = truncate_headers(
+            format!("{:?}", redact_headers(headers)),
+            max_header_capture_bytes,
+        );
+        truncate(headers, max_value_length)
+    };
+    if capture_headers == CaptureHeaders::Always {
+        span.record("http.request.headers", headers_dump(req.headers()));
+    }
+    record_headers(
+        &span,
+        req.headers(),
+        &CAPTURED_REQUEST_HEADERS,
+        max_value_length,
     );
+    if let Some(content_type) = request_content_type(req.headers()) {
+        span.record(
+            HTTP_REQUEST_HEADER_CONTENT_TYPE,
+            truncate(content_type, max_value_length),
+        );
+    }
+    span.record(NETWORK_PROTOCOL_VERSION, field::debug(req.version()));
+    if let Some(worker_index) = req.app_data::<web::Data<WorkerIndex>>() {
+        span.record(ACTIX_WORKER_INDEX, worker_index.0 as i64);
+    }
+    span.record(TLS, scheme == "https");
+    span.record(URL_SCHEME, scheme);
+    // Unix sockets and the test harness have no peer address; leave the
+    // attribute out rather than recording an empty string.
+    span.record(CLIENT_ADDRESS, req.connection_info().peer_addr());
 
     if let Some(user_agent) = req.headers().get("User-Agent") {
         span.record(USER_AGENT_ORIGINAL, user_agent.to_str().unwrap_or_default());
     }
 
+    let declared_body_size = declared_body_size(req.headers());
+    let max_counted_body_size = config.and_then(|config| config.max_counted_body_size);
+    let body_size = match (declared_body_size, max_counted_body_size) {
+        (Some(declared), Some(max)) if declared > max => None,
+        _ => Some(count_payload(&mut req)),
+    };
+
+    let resp = next.call(req).await;
+    tracing::event!(parent: &span, tracing::Level::INFO, "handler.completed");
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(err) => {
+            // Errors returned by inner middleware skip the response, so the
+            // span is the only place they are recorded.
+            let status = err.as_response_error().status_code();
+            span.record(HTTP_RESPONSE_STATUS_CODE, field::display(status));
+            if is_error(status, treat_4xx_as_error) {
+                record_error(&span, status, None, Some(&err));
+            }
+            return Err(err);
+        }
+    };
+    let (req, mut res) = resp.into_parts();
+
+    match body_size {
+        Some(body_size) => {
+            if let Some(actual_body_size) = body_size.get() {
+                span.record(HTTP_REQUEST_BODY_SIZE, actual_body_size as i64);
+                if declared_body_size.is_some_and(|declared| declared != actual_body_size) {
+                    span.record(HTTP_REQUEST_BODY_LENGTH_MISMATCH, true);
+                }
+            }
+        }
+        None => {
+            span.record(
+                HTTP_REQUEST_BODY_SIZE,
+                declared_body_size.map(|size| size as i64),
+            );
+        }
+    }
+
+    if let Some(request_context) = req.extensions().get::<RequestContext>() {
+        for attribute in &request_context.attributes {
+            span.set_attribute(attribute.key.clone(), attribute.value.clone());
+        }
+    }
+    if let Some(FeatureFlags(flags)) = req.extensions().get::<FeatureFlags>() {
+        for (key, variant) in flags {
+            span.set_attribute(format!("{FEATURE_FLAG}.{key}"), variant.clone());
+        }
+    }
+    if let Some(SpanName(name)) = req.extensions().get::<SpanName>() {
+        span.record("otel.name", name.as_str());
+    }
+    if record_enduser_id {
+        if let Some(EndUserId(id)) = req.extensions().get::<EndUserId>() {
+            span.record(ENDUSER_ID, id.as_str());
+        }
+    }
+
     span.record(HTTP_RESPONSE_STATUS_CODE, field::display(res.status()));
-    if !res.status().is_success() {
-        span.record(ERROR_TYPE, field::display(res.status()));
+    if capture_headers == CaptureHeaders::OnError
+        && (res.status().is_client_error() || res.status().is_server_error())
+    {
+        span.record("http.request.headers", headers_dump(req.headers()));
+    }
+    record_headers(
+        &span,
+        res.headers(),
+        &CAPTURED_RESPONSE_HEADERS,
+        max_value_length,
+    );
+    let error_type = res
+        .extensions()
+        .get::<ErrorType>()
+        .map(|error_type| error_type.0);
+    if error_type.is_some() || is_error(res.status(), treat_4xx_as_error) {
+        record_error(&span, res.status(), error_type, res.error());
+    }
+    if let Some(CacheHit(hit)) = res.extensions().get::<CacheHit>() {
+        span.record(CACHE_HIT, *hit);
+    }
+    if grpc {
+        record_grpc_status(&span, res.headers());
+    }
+    if expose_trace_id && res.status().is_server_error() && trace_id != TraceId::INVALID {
+        res.headers_mut().insert(
+            HeaderName::from_static(X_TRACE_ID),
+            HeaderValue::from_str(&trace_id.to_string()).expect("hex is a valid header value"),
+        );
     }
 
+    let res = res.map_body(|_, body| TracedBody::new(body, Some(span)));
     let res = ServiceResponse::new(req, res);
 
     Ok(res)
@@ -109,15 +779,77 @@ pub async fn record_trace(
 #[cfg(test)]
 mod tests {
     use crate::api::route;
-    use crate::middleware::tracing::record_trace;
-    use actix_web::middleware::from_fn;
-    use actix_web::{test, App};
+    use crate::middleware::tracing::{
+        record_trace, route_param_names, template_path, truncate, CacheHit, EndUserId,
+        FeatureFlags, RequestContext, SpanName, TracedRequestsFilter, WorkerIndex,
+        ACTIX_WORKER_INDEX, CACHE_HIT, ENDUSER_ID, HTTP_REQUEST_HEADER_ACCEPT,
+        HTTP_RESPONSE_HEADER_CONTENT_ENCODING, HTTP_ROUTE_PARAMS, SAMPLING_DECISION,
+        SAMPLING_RATIO, TLS, TRACE_REMOTE_PARENT, X_TRACE_ID,
+    };
+    use crate::middleware::HTTP_REQUEST_HEADER_CONTENT_TYPE;
+    use crate::telemetry::propagation::build_propagator;
+    use crate::{CaptureHeaders, Propagator, SpanLevel, TracingConfig};
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{ServiceRequest, ServiceResponse};
+    use actix_web::http::header::{HeaderMap, CONTENT_LENGTH};
+    use actix_web::http::Method;
+    use actix_web::middleware::{from_fn, Compress, Next};
+    use actix_web::{
+        error, get, post, test, web, App, Error, HttpMessage, HttpRequest, HttpResponse,
+    };
     use opentelemetry::global::shutdown_tracer_provider;
-    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::trace::{SpanId, Status, TracerProvider as _};
+    use opentelemetry::{Key, KeyValue, Value};
+    use opentelemetry_sdk::export::trace::SpanData;
     use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
-    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+    use opentelemetry_semantic_conventions::trace::{
+        CLIENT_ADDRESS, ERROR_TYPE, EXCEPTION_MESSAGE, HTTP_REQUEST_METHOD,
+        HTTP_REQUEST_METHOD_ORIGINAL, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE, RPC_GRPC_STATUS_CODE,
+        URL_PATH, URL_SCHEME,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::subscriber::DefaultGuard;
+    use tracing::Subscriber;
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::Context;
     use tracing_subscriber::layer::SubscriberExt;
     use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::Layer;
+
+    fn init_test_tracer() -> (InMemorySpanExporter, DefaultGuard) {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+
+        let tracer = provider.tracer("test_tracer");
+        let trace_layer = tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(TracedRequestsFilter);
+        let guard = tracing_subscriber::registry()
+            .with(trace_layer)
+            .set_default();
+        (exporter, guard)
+    }
+
+    fn find_span<'a>(spans: &'a [SpanData], name: &str) -> &'a SpanData {
+        spans
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("span {name} not found"))
+    }
+
+    fn attribute(span: &SpanData, key: &'static str) -> Option<Value> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key == Key::from_static_str(key))
+            .map(|kv| kv.value.clone())
+    }
 
     #[tokio::test]
     async fn test_tracing() {
@@ -142,4 +874,1458 @@ mod tests {
 
         shutdown_tracer_provider();
     }
+
+    async fn insert_request_context(
+        req: ServiceRequest,
+        next: Next<impl MessageBody>,
+    ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        req.extensions_mut().insert(RequestContext::new(vec![
+            KeyValue::new("tenant.id", "tenant-1"),
+            KeyValue::new("enduser.role", "admin"),
+        ]));
+        next.call(req).await
+    }
+
+    #[tokio::test]
+    async fn test_request_context_attributes() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(insert_request_context))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(
+            attribute(request_span, "tenant.id"),
+            Some(Value::from("tenant-1"))
+        );
+        assert_eq!(
+            attribute(request_span, "enduser.role"),
+            Some(Value::from("admin"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_route_server_error() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::get().uri("/error/500").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 500);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /error/{code}");
+        assert_eq!(
+            attribute(request_span, HTTP_RESPONSE_STATUS_CODE),
+            Some(Value::from("500 Internal Server Error"))
+        );
+        assert_eq!(
+            attribute(request_span, ERROR_TYPE),
+            Some(Value::from("500 Internal Server Error"))
+        );
+        assert_eq!(
+            request_span.status,
+            Status::error("HTTP 500 Internal Server Error")
+        );
+    }
+
+    async fn client_error_span(treat_4xx_as_error: bool) -> SpanData {
+        let (exporter, _guard) = init_test_tracer();
+
+        let tracing_config = TracingConfig {
+            treat_4xx_as_error,
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(tracing_config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/error/404").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /error/{code}").clone();
+        assert_eq!(
+            attribute(&request_span, HTTP_RESPONSE_STATUS_CODE),
+            Some(Value::from("404 Not Found"))
+        );
+        request_span
+    }
+
+    #[tokio::test]
+    async fn test_error_route_client_error() {
+        let request_span = client_error_span(false).await;
+        assert_eq!(attribute(&request_span, ERROR_TYPE), None);
+        assert_eq!(request_span.status, Status::Unset);
+    }
+
+    #[tokio::test]
+    async fn test_error_route_client_error_treated_as_error() {
+        let request_span = client_error_span(true).await;
+        assert_eq!(
+            attribute(&request_span, ERROR_TYPE),
+            Some(Value::from("404 Not Found"))
+        );
+        assert_eq!(request_span.status, Status::error("HTTP 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn test_error_route_rejects_non_error_code() {
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::get().uri("/error/200").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_timing_events() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).route(
+            "/slow",
+            web::get().to(|| async {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                HttpResponse::Ok().streaming(futures_util::stream::once(async {
+                    tokio::time::sleep(Duration::from_millis(2)).await;
+                    Ok::<_, Error>(web::Bytes::from("done"))
+                }))
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, "done");
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /slow");
+        let timestamp = |name: &str| {
+            request_span
+                .events
+                .iter()
+                .find(|event| event.name == name)
+                .unwrap_or_else(|| panic!("event {name} not found"))
+                .timestamp
+        };
+        let received = timestamp("request.received");
+        let handled = timestamp("handler.completed");
+        let sent = timestamp("response.sent");
+        assert!(received < handled);
+        assert!(handled < sent);
+    }
+
+    #[tokio::test]
+    async fn test_request_received_event() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::get()
+            .uri("/error/404")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /error/{code}");
+        let event = request_span
+            .events
+            .iter()
+            .find(|event| event.name == "request.received")
+            .unwrap();
+        let event_attribute = |key: &'static str| {
+            event
+                .attributes
+                .iter()
+                .find(|kv| kv.key == Key::from_static_str(key))
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(
+            event_attribute(HTTP_REQUEST_METHOD),
+            Some(Value::from("GET"))
+        );
+        assert_eq!(
+            event_attribute(HTTP_ROUTE),
+            Some(Value::from("/error/{code}"))
+        );
+        assert_eq!(
+            event_attribute(CLIENT_ADDRESS),
+            Some(Value::from("127.0.0.1"))
+        );
+    }
+
+    /// Collects the names of span fields as they are recorded.
+    #[derive(Clone, Default)]
+    struct RecordedFields(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for RecordedFields {
+        fn record_debug(&mut self, field: &Field, _value: &dyn std::fmt::Debug) {
+            self.0.lock().unwrap().push(field.name().to_string());
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for RecordedFields {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            attrs.record(&mut self.clone());
+        }
+
+        fn on_record(&self, _id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_attributes_recorded_before_handler() {
+        let recorded_fields = RecordedFields::default();
+        let _guard = tracing_subscriber::registry()
+            .with(recorded_fields.clone())
+            .set_default();
+
+        let in_flight_fields = Arc::new(Mutex::new(Vec::new()));
+        let snapshot = in_flight_fields.clone();
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(move |req: ServiceRequest, next: Next<_>| {
+                    *snapshot.lock().unwrap() = recorded_fields.0.lock().unwrap().clone();
+                    next.call(req)
+                }))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let in_flight_fields = in_flight_fields.lock().unwrap();
+        for key in [URL_PATH, HTTP_ROUTE, HTTP_REQUEST_METHOD, CLIENT_ADDRESS] {
+            assert!(in_flight_fields.iter().any(|field| field == key));
+        }
+        assert!(!in_flight_fields
+            .iter()
+            .any(|field| field == HTTP_RESPONSE_STATUS_CODE));
+    }
+
+    /// Collects span field values, formatted with `Debug`, keyed by name.
+    #[derive(Clone, Default)]
+    struct RecordedValues(Arc<Mutex<HashMap<String, String>>>);
+
+    impl Visit for RecordedValues {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for RecordedValues {
+        fn on_record(&self, _id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sampling_decision_drop() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_config(
+                opentelemetry_sdk::trace::Config::default()
+                    .with_sampler(Sampler::TraceIdRatioBased(0.0)),
+            )
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let recorded_values = RecordedValues::default();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test_tracer")))
+            .with(recorded_values.clone())
+            .set_default();
+
+        let tracing_config = TracingConfig {
+            sampling_ratio: Some(0.0),
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(tracing_config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let values = recorded_values.0.lock().unwrap();
+        assert_eq!(values.get(SAMPLING_DECISION).unwrap(), "drop");
+        assert_eq!(values.get(SAMPLING_RATIO).unwrap(), "0.0");
+        assert!(exporter.get_finished_spans().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sampling_decision_sample() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(
+            attribute(request_span, SAMPLING_DECISION),
+            Some(Value::from("record_and_sample"))
+        );
+        assert_eq!(
+            attribute(request_span, SAMPLING_RATIO),
+            Some(Value::F64(1.0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scoped_route_template() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(record_trace))
+                .service(web::scope("/api/v1").configure(route)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/api/v1/error/503")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /api/v1/error/{code}");
+        assert_eq!(
+            attribute(request_span, HTTP_ROUTE),
+            Some(Value::from("/api/v1/error/{code}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_body_size() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header((CONTENT_LENGTH, "5"))
+            .set_payload("hello")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "POST /echo");
+        assert_eq!(
+            attribute(request_span, "http.request.body.size"),
+            Some(Value::I64(5))
+        );
+        assert_eq!(
+            attribute(request_span, "http.request.body.length_mismatch"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_body_length_mismatch() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header((CONTENT_LENGTH, "10"))
+            .set_payload("hello")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "POST /echo");
+        assert_eq!(
+            attribute(request_span, "http.request.body.size"),
+            Some(Value::I64(5))
+        );
+        assert_eq!(
+            attribute(request_span, "http.request.body.length_mismatch"),
+            Some(Value::Bool(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_body_size_from_content_length() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let config = TracingConfig {
+            max_counted_body_size: Some(4),
+            ..TracingConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header((CONTENT_LENGTH, "10"))
+            .set_payload("hello")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        drop(resp);
+
+        // Not counted, so the declared length is recorded and the mismatch
+        // goes unnoticed.
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "POST /echo");
+        assert_eq!(
+            attribute(request_span, "http.request.body.size"),
+            Some(Value::I64(10))
+        );
+        assert_eq!(
+            attribute(request_span, "http.request.body.length_mismatch"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_span_links() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let tracing_config = TracingConfig {
+            link_header: Some("x-link-traceparent".to_string()),
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(tracing_config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((
+                "x-link-traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01, \
+                 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        let linked_trace_ids = request_span
+            .links
+            .iter()
+            .map(|link| link.span_context.trace_id().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            linked_trace_ids,
+            [
+                "0af7651916cd43dd8448eb211c80319c",
+                "4bf92f3577b34da6a3ce929d0e0e4736"
+            ]
+        );
+        assert_eq!(request_span.parent_span_id, SpanId::INVALID);
+    }
+
+    #[tokio::test]
+    async fn test_tracestate_propagation() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(build_propagator(&[
+                    Propagator::TraceContext,
+                ])))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            ))
+            .insert_header(("tracestate", "vendor1=abc,vendor2=xyz"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(
+            request_span.span_context.trace_id().to_string(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(
+            request_span.parent_span_id,
+            SpanId::from_hex("b7ad6b7169203331").unwrap()
+        );
+        assert_eq!(
+            request_span.span_context.trace_state().header(),
+            "vendor1=abc,vendor2=xyz"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_headers_truncated() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let tracing_config = TracingConfig {
+            max_attribute_value_length: Some(64),
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(tracing_config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("x-large", "a".repeat(1024)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        let headers = attribute(request_span, "http.request.headers").unwrap();
+        assert_eq!(headers.as_str().len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_accept_header_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header(("accept", "application/json"))
+            .set_payload("hello")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "POST /echo");
+        assert_eq!(
+            attribute(request_span, HTTP_REQUEST_HEADER_ACCEPT),
+            Some(Value::from("application/json"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_type_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header(("content-type", "application/json; charset=utf-8"))
+            .set_payload(r#"{"name": "report"}"#)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "POST /echo");
+        assert_eq!(
+            attribute(request_span, HTTP_REQUEST_HEADER_CONTENT_TYPE),
+            Some(Value::from("application/json"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_method_recorded_as_other() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(record_trace))
+                .route("/cache", web::route().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .method(Method::from_bytes(b"PURGE").unwrap())
+            .uri("/cache")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "HTTP /cache");
+        assert_eq!(
+            attribute(request_span, HTTP_REQUEST_METHOD),
+            Some(Value::from("_OTHER"))
+        );
+        assert_eq!(
+            attribute(request_span, HTTP_REQUEST_METHOD_ORIGINAL),
+            Some(Value::from("PURGE"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_worker_index_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(WorkerIndex(3)))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(
+            attribute(request_span, ACTIX_WORKER_INDEX),
+            Some(Value::I64(3))
+        );
+        assert!(attribute(request_span, "thread.name").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_content_encoding_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Compress::default())
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("accept-encoding", "gzip"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let encodings = spans
+            .iter()
+            .filter(|span| span.name == "GET /")
+            .map(|span| attribute(span, HTTP_RESPONSE_HEADER_CONTENT_ENCODING))
+            .collect::<Vec<_>>();
+        assert_eq!(encodings, [Some(Value::from("gzip")), None]);
+    }
+
+    #[tokio::test]
+    async fn test_request_headers_capped() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let config = TracingConfig {
+            max_header_capture_bytes: Some(64),
+            ..TracingConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("x-large", "a".repeat(1024)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        let Some(Value::String(headers)) = attribute(request_span, "http.request.headers") else {
+            panic!("http.request.headers not recorded");
+        };
+        assert_eq!(headers.as_str().len(), 64 + "...[truncated]".len());
+        assert!(headers.as_str().ends_with("...[truncated]"));
+    }
+
+    /// Whether a success and an error response record `http.request.headers`
+    /// with `capture_headers`.
+    async fn headers_captured(capture_headers: CaptureHeaders) -> [bool; 2] {
+        let (exporter, _guard) = init_test_tracer();
+
+        let config = TracingConfig {
+            capture_headers,
+            ..TracingConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        for uri in ["/", "/error/503"] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("authorization", "Bearer secret-token"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            drop(resp);
+        }
+
+        let spans = exporter.get_finished_spans().unwrap();
+        ["GET /", "GET /error/{code}"].map(|name| {
+            let headers = attribute(find_span(&spans, name), "http.request.headers");
+            if let Some(headers) = &headers {
+                assert!(!headers.as_str().contains("secret-token"));
+                assert!(headers.as_str().contains("[REDACTED]"));
+            }
+            headers.is_some()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_capture_headers_modes() {
+        assert_eq!(headers_captured(CaptureHeaders::Always).await, [true, true]);
+        assert_eq!(
+            headers_captured(CaptureHeaders::Never).await,
+            [false, false]
+        );
+        assert_eq!(
+            headers_captured(CaptureHeaders::OnError).await,
+            [false, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_url_path_templated() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let config = TracingConfig {
+            template_url_path: true,
+            ..TracingConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .route(
+                    "/users/{id}/orders/{order:\\d+}",
+                    web::get().to(HttpResponse::Ok),
+                ),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/users/alice/orders/42")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /users/{id}/orders/{order:\\d+}");
+        assert_eq!(
+            attribute(request_span, URL_PATH),
+            Some(Value::from("/users/{id}/orders/{order}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_params_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(record_trace))
+                .route("/org/{org}/user/{id}", web::get().to(HttpResponse::Ok))
+                .route("/health", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for uri in ["/org/acme/user/42", "/health"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(
+            attribute(
+                find_span(&spans, "GET /org/{org}/user/{id}"),
+                HTTP_ROUTE_PARAMS
+            ),
+            Some(Value::from("org,id"))
+        );
+        assert_eq!(
+            attribute(find_span(&spans, "GET /health"), HTTP_ROUTE_PARAMS),
+            None
+        );
+    }
+
+    #[::core::prelude::v1::test]
+    fn test_route_param_names() {
+        assert_eq!(
+            route_param_names("/users/{id}/orders/{order:\\d+}"),
+            "id,order"
+        );
+        assert_eq!(route_param_names("/code/{code:\\d{3}}/{rest}"), "code,rest");
+        assert_eq!(route_param_names("/static/{tail}*"), "tail");
+        assert_eq!(route_param_names("/health"), "");
+    }
+
+    #[::core::prelude::v1::test]
+    fn test_template_path_tail_match() {
+        assert_eq!(
+            template_path("/static/css/app.css", "/static/{tail}*"),
+            "/static/{tail}*"
+        );
+    }
+
+    #[::core::prelude::v1::test]
+    fn test_template_path_partial_segments() {
+        assert_eq!(
+            template_path("/v2/user-42/orders", "/v{version}/user-{id:\\d+}/orders"),
+            "/v{version}/user-{id}/orders"
+        );
+        assert_eq!(
+            template_path("/code/404", "/code/{code:\\d{3}}"),
+            "/code/{code}"
+        );
+    }
+
+    // `test` is actix_web::test in this module.
+    #[::core::prelude::v1::test]
+    fn test_truncate_char_boundary() {
+        assert_eq!(truncate("héllo".to_string(), Some(2)), "h");
+        assert_eq!(truncate("héllo".to_string(), Some(3)), "hé");
+        assert_eq!(truncate("héllo".to_string(), None), "héllo");
+    }
+
+    #[tokio::test]
+    async fn test_default_span_attributes() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let tracing_config = TracingConfig {
+            span_attributes: [("deployment.region".to_string(), "eu-west-1".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(tracing_config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        for uri in ["/", "/error/503"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let spans = exporter.get_finished_spans().unwrap();
+        for name in ["GET /", "GET /error/{code}"] {
+            assert_eq!(
+                attribute(find_span(&spans, name), "deployment.region"),
+                Some(Value::from("eu-west-1"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_peer_addr() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).configure(route)).await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        assert!(req.peer_addr().is_none());
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(attribute(request_span, CLIENT_ADDRESS), None);
+        let event = request_span
+            .events
+            .iter()
+            .find(|event| event.name == "request.received")
+            .unwrap();
+        assert!(!event
+            .attributes
+            .iter()
+            .any(|kv| kv.key == Key::from_static_str(CLIENT_ADDRESS)));
+    }
+
+    #[tokio::test]
+    async fn test_trace_remote_parent() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(build_propagator(&[
+                    Propagator::TraceContext,
+                ])))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let requests = [
+            test::TestRequest::get()
+                .uri("/")
+                .insert_header((
+                    "traceparent",
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                ))
+                .to_request(),
+            test::TestRequest::get().uri("/").to_request(),
+        ];
+        for req in requests {
+            test::call_service(&app, req).await;
+        }
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let remote_parent = spans
+            .iter()
+            .filter(|span| span.name == "GET /")
+            .map(|span| attribute(span, TRACE_REMOTE_PARENT))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            remote_parent,
+            [Some(Value::Bool(true)), Some(Value::Bool(false))]
+        );
+        let sampling_ratio = spans
+            .iter()
+            .filter(|span| span.name == "GET /")
+            .map(|span| attribute(span, SAMPLING_RATIO))
+            .collect::<Vec<_>>();
+        assert_eq!(sampling_ratio, [None, Some(Value::F64(1.0))]);
+    }
+
+    async fn forwarded_https_span(trust_forwarded_proto: bool) -> SpanData {
+        let (exporter, _guard) = init_test_tracer();
+
+        let tracing_config = TracingConfig {
+            trust_forwarded_proto,
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(tracing_config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("x-forwarded-proto", "https"))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let spans = exporter.get_finished_spans().unwrap();
+        find_span(&spans, "GET /").clone()
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_proto_trusted() {
+        let request_span = forwarded_https_span(true).await;
+        assert_eq!(
+            attribute(&request_span, URL_SCHEME),
+            Some(Value::from("https"))
+        );
+        assert_eq!(attribute(&request_span, TLS), Some(Value::Bool(true)));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_proto_ignored_by_default() {
+        let request_span = forwarded_https_span(false).await;
+        assert_eq!(
+            attribute(&request_span, URL_SCHEME),
+            Some(Value::from("http"))
+        );
+        assert_eq!(attribute(&request_span, TLS), Some(Value::Bool(false)));
+    }
+
+    #[get("/fail")]
+    async fn fail() -> Result<&'static str, Error> {
+        Err(error::ErrorBadGateway("upstream unavailable"))
+    }
+
+    #[tokio::test]
+    async fn test_handler_error_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).service(fail)).await;
+        let req = test::TestRequest::get().uri("/fail").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 502);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /fail");
+        assert_eq!(
+            attribute(request_span, ERROR_TYPE),
+            Some(Value::from("502 Bad Gateway"))
+        );
+        assert_eq!(
+            attribute(request_span, EXCEPTION_MESSAGE),
+            Some(Value::from("upstream unavailable"))
+        );
+        assert_eq!(request_span.status, Status::error("HTTP 502 Bad Gateway"));
+    }
+
+    #[tokio::test]
+    async fn test_middleware_error_recorded_and_propagated() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(|_req: ServiceRequest, _next: Next<_>| async {
+                    Err::<ServiceResponse, _>(error::ErrorServiceUnavailable("draining"))
+                }))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let Err(err) = test::try_call_service(&app, req).await else {
+            panic!("expected the middleware error to propagate");
+        };
+        assert_eq!(err.to_string(), "draining");
+        drop(err);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(
+            attribute(request_span, HTTP_RESPONSE_STATUS_CODE),
+            Some(Value::from("503 Service Unavailable"))
+        );
+        assert_eq!(
+            attribute(request_span, ERROR_TYPE),
+            Some(Value::from("503 Service Unavailable"))
+        );
+        assert_eq!(
+            attribute(request_span, EXCEPTION_MESSAGE),
+            Some(Value::from("draining"))
+        );
+        assert_eq!(
+            request_span.status,
+            Status::error("HTTP 503 Service Unavailable")
+        );
+    }
+
+    #[post("/rpc")]
+    async fn rpc(req: HttpRequest, operation: String) -> &'static str {
+        req.extensions_mut()
+            .insert(SpanName(format!("rpc {operation}")));
+        "ok"
+    }
+
+    #[tokio::test]
+    async fn test_span_name_override() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(App::new().wrap(from_fn(record_trace)).service(rpc)).await;
+        let req = test::TestRequest::post()
+            .uri("/rpc")
+            .set_payload("GetUser")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "rpc GetUser");
+        assert_eq!(
+            attribute(request_span, HTTP_ROUTE),
+            Some(Value::from("/rpc"))
+        );
+    }
+
+    async fn authenticate(
+        req: ServiceRequest,
+        next: Next<impl MessageBody>,
+    ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        req.extensions_mut()
+            .insert(EndUserId("user-42".to_string()));
+        next.call(req).await
+    }
+
+    async fn authenticated_span(record_enduser_id: bool) -> SpanData {
+        let (exporter, _guard) = init_test_tracer();
+        let config = TracingConfig {
+            record_enduser_id,
+            ..TracingConfig::default()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .wrap(from_fn(authenticate))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        find_span(&spans, "GET /").clone()
+    }
+
+    #[tokio::test]
+    async fn test_enduser_id_recorded() {
+        let span = authenticated_span(true).await;
+        assert_eq!(attribute(&span, ENDUSER_ID), Some(Value::from("user-42")));
+    }
+
+    #[tokio::test]
+    async fn test_enduser_id_off_by_default() {
+        let span = authenticated_span(false).await;
+        assert_eq!(attribute(&span, ENDUSER_ID), None);
+    }
+
+    async fn assign_experiments(
+        req: ServiceRequest,
+        next: Next<impl MessageBody>,
+    ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        req.extensions_mut().insert(FeatureFlags(
+            [
+                ("new-checkout".to_string(), "treatment".to_string()),
+                ("dark-mode".to_string(), "off".to_string()),
+            ]
+            .into(),
+        ));
+        next.call(req).await
+    }
+
+    #[tokio::test]
+    async fn test_feature_flags_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(record_trace))
+                .wrap(from_fn(assign_experiments))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(
+            attribute(request_span, "feature_flag.new-checkout"),
+            Some(Value::from("treatment"))
+        );
+        assert_eq!(
+            attribute(request_span, "feature_flag.dark-mode"),
+            Some(Value::from("off"))
+        );
+    }
+
+    #[get("/cached")]
+    async fn cached() -> HttpResponse {
+        let mut res = HttpResponse::Ok().body("from cache");
+        res.extensions_mut().insert(CacheHit(true));
+        res
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_recorded() {
+        let (exporter, _guard) = init_test_tracer();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(record_trace))
+                .service(cached)
+                .configure(route),
+        )
+        .await;
+        for uri in ["/cached", "/"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+            drop(resp);
+        }
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(
+            attribute(find_span(&spans, "GET /cached"), CACHE_HIT),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(attribute(find_span(&spans, "GET /"), CACHE_HIT), None);
+    }
+
+    #[post("/grpc.health.v1.Health/Check")]
+    async fn grpc_check() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/grpc")
+            .insert_header(("grpc-status", "14"))
+            .insert_header(("grpc-message", "backend unavailable"))
+            .finish()
+    }
+
+    async fn grpc_span(grpc: bool) -> SpanData {
+        let (exporter, _guard) = init_test_tracer();
+        let config = TracingConfig {
+            grpc,
+            ..TracingConfig::default()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .service(grpc_check),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/grpc.health.v1.Health/Check")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        find_span(&spans, "POST /grpc.health.v1.Health/Check").clone()
+    }
+
+    #[tokio::test]
+    async fn test_grpc_status_error() {
+        let span = grpc_span(true).await;
+        assert_eq!(attribute(&span, RPC_GRPC_STATUS_CODE), Some(Value::I64(14)));
+        assert_eq!(attribute(&span, ERROR_TYPE), Some(Value::from("14")));
+        assert_eq!(
+            attribute(&span, EXCEPTION_MESSAGE),
+            Some(Value::from("backend unavailable"))
+        );
+        assert_eq!(span.status, Status::error("gRPC status 14"));
+    }
+
+    #[tokio::test]
+    async fn test_grpc_status_ignored_by_default() {
+        let span = grpc_span(false).await;
+        assert_eq!(attribute(&span, RPC_GRPC_STATUS_CODE), None);
+        assert_eq!(span.status, Status::Unset);
+    }
+
+    #[tokio::test]
+    async fn test_route_sample_rate() {
+        let (exporter, _guard) = init_test_tracer();
+        let config = TracingConfig {
+            route_sample_rates: [("/".to_string(), 0.0)].into(),
+            ..TracingConfig::default()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        // Neither the request span nor the spans of the hello handler.
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 0);
+
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .set_payload("hello")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+        let spans = exporter.get_finished_spans().unwrap();
+        find_span(&spans, "POST /echo");
+    }
+
+    #[tokio::test]
+    async fn test_trace_only_matched_routes() {
+        let (exporter, _guard) = init_test_tracer();
+        let config = TracingConfig {
+            trace_only_matched_routes: true,
+            ..TracingConfig::default()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .configure(route)
+                .default_service(web::to(|| async {
+                    let _span = tracing::info_span!("not found").entered();
+                    HttpResponse::NotFound().finish()
+                })),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/wp-login.php").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+        drop(resp);
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        // Only GET / and the spans of the hello handler under it.
+        let spans = exporter.get_finished_spans().unwrap();
+        let mut names = spans
+            .iter()
+            .map(|span| span.name.as_ref())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            ["GET /", "foo", "hello", "this is inside the foo func"]
+        );
+        assert_eq!(
+            attribute(find_span(&spans, "GET /"), URL_PATH),
+            Some(Value::from("/"))
+        );
+    }
+
+    async fn server_error_response(expose_trace_id: bool) -> (HeaderMap, SpanData) {
+        let (exporter, _guard) = init_test_tracer();
+        let config = TracingConfig {
+            expose_trace_id,
+            ..TracingConfig::default()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/error/500").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 500);
+        let headers = resp.headers().clone();
+        drop(resp);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let span = find_span(&spans, "GET /error/{code}").clone();
+        (headers, span)
+    }
+
+    #[tokio::test]
+    async fn test_trace_id_exposed_on_server_error() {
+        let (headers, span) = server_error_response(true).await;
+        assert_eq!(
+            headers.get(X_TRACE_ID).unwrap(),
+            span.span_context.trace_id().to_string().as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace_id_hidden_by_default() {
+        let (headers, _span) = server_error_response(false).await;
+        assert!(headers.get(X_TRACE_ID).is_none());
+    }
+
+    async fn exported_spans_at_warn(span_level: SpanLevel) -> Vec<SpanData> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test_tracer")))
+            .with(LevelFilter::WARN)
+            .set_default();
+
+        let tracing_config = TracingConfig {
+            span_level,
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(tracing_config))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        exporter.get_finished_spans().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_span_level_above_filter() {
+        let spans = exported_spans_at_warn(SpanLevel::Warn).await;
+        let request_span = find_span(&spans, "GET /");
+        assert_eq!(attribute(request_span, HTTP_ROUTE), Some(Value::from("/")));
+    }
+
+    #[tokio::test]
+    async fn test_span_level_below_filter() {
+        let spans = exported_spans_at_warn(SpanLevel::Info).await;
+        assert!(!spans.iter().any(|span| span.name == "GET /"));
+    }
 }
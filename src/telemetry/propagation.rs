@@ -0,0 +1,197 @@
+//! Which propagators carry context between services, and the carriers it is
+//! read from and written to.
+
+use crate::Propagator;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::propagation::{
+    Extractor, Injector, TextMapCompositePropagator, TextMapPropagator,
+};
+use opentelemetry::Context;
+use opentelemetry_sdk::propagation::{BaggagePropagator, TraceContextPropagator};
+
+/// Combines `propagators` in order. W3C trace context reads `traceparent` and
+/// `tracestate` together; baggage reads the `baggage` header.
+pub fn build_propagator(propagators: &[Propagator]) -> TextMapCompositePropagator {
+    let propagators = propagators
+        .iter()
+        .map(|propagator| -> Box<dyn TextMapPropagator + Send + Sync> {
+            match propagator {
+                Propagator::TraceContext => Box::new(TraceContextPropagator::new()),
+                Propagator::Baggage => Box::new(BaggagePropagator::new()),
+            }
+        })
+        .collect();
+    TextMapCompositePropagator::new(propagators)
+}
+
+/// Installs the propagators [extract_context] and [inject_context] use.
+pub fn register(propagators: &[Propagator]) {
+    opentelemetry::global::set_text_map_propagator(build_propagator(propagators));
+}
+
+/// Request metadata trace context can be propagated in: HTTP headers, and
+/// gRPC metadata for services that also serve tonic.
+pub trait Carrier {
+    /// The value of `key` if it is valid text.
+    fn get(&self, key: &str) -> Option<&str>;
+
+    fn keys(&self) -> Vec<&str>;
+}
+
+impl Carrier for HeaderMap {
+    fn get(&self, key: &str) -> Option<&str> {
+        HeaderMap::get(self, key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        HeaderMap::keys(self).map(HeaderName::as_str).collect()
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl Carrier for tonic::metadata::MetadataMap {
+    fn get(&self, key: &str) -> Option<&str> {
+        tonic::metadata::MetadataMap::get(self, key).and_then(|value| value.to_str().ok())
+    }
+
+    /// Binary (`-bin`) entries can't carry text context and are skipped.
+    fn keys(&self) -> Vec<&str> {
+        tonic::metadata::MetadataMap::keys(self)
+            .filter_map(|key| match key {
+                tonic::metadata::KeyRef::Ascii(key) => Some(key.as_str()),
+                tonic::metadata::KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Reads propagated context from any [Carrier].
+pub struct HeaderExtractor<'a, C: ?Sized>(pub &'a C);
+
+impl<C: Carrier + ?Sized> Extractor for HeaderExtractor<'_, C> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys()
+    }
+}
+
+/// Writes propagated context into HTTP headers. Values that aren't valid
+/// header values are skipped.
+pub struct HeaderInjector<'a>(pub &'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// The remote parent propagated in `carrier`, read with the global propagator.
+pub fn extract_context<C: Carrier + ?Sized>(carrier: &C) -> Context {
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(carrier))
+    })
+}
+
+/// Writes `context` into `headers` with the global propagator, e.g. to return
+/// the server span's context in a response.
+pub fn inject_context(context: &Context, headers: &mut HeaderMap) {
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(context, &mut HeaderInjector(headers))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_propagator, HeaderExtractor, HeaderInjector};
+    use crate::Propagator;
+    use actix_web::http::header::HeaderMap;
+    use opentelemetry::baggage::BaggageExt;
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry::{Context, KeyValue};
+
+    fn remote_context() -> Context {
+        Context::new()
+            .with_remote_span_context(SpanContext::new(
+                TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+                SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+                TraceFlags::SAMPLED,
+                true,
+                TraceState::from_key_value([("vendor", "value")]).unwrap(),
+            ))
+            .with_baggage([KeyValue::new("tenant", "acme")])
+    }
+
+    #[test]
+    fn test_inject_extract_round_trip() {
+        let propagator = build_propagator(&[Propagator::TraceContext, Propagator::Baggage]);
+        let context = remote_context();
+        let mut headers = HeaderMap::new();
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers));
+        assert_eq!(
+            headers.get("traceparent").unwrap(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+
+        let extracted = propagator.extract(&HeaderExtractor(&headers));
+        assert_eq!(
+            extracted.span().span_context(),
+            context.span().span_context()
+        );
+        assert_eq!(
+            extracted
+                .baggage()
+                .get("tenant")
+                .map(|value| value.as_str()),
+            Some("acme".into())
+        );
+    }
+
+    #[test]
+    fn test_baggage_only_when_selected() {
+        let propagator = build_propagator(&[Propagator::TraceContext]);
+        let mut headers = HeaderMap::new();
+        propagator.inject_context(&remote_context(), &mut HeaderInjector(&mut headers));
+        assert!(headers.contains_key("traceparent"));
+        assert!(!headers.contains_key("baggage"));
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_extract_context_from_grpc_metadata() {
+        let mut metadata = tonic::metadata::MetadataMap::new();
+        metadata.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        metadata.insert_bin(
+            "trace-proto-bin",
+            tonic::metadata::MetadataValue::from_bytes(b"\x00"),
+        );
+
+        let propagator = build_propagator(&[Propagator::TraceContext]);
+        let context = propagator.extract(&HeaderExtractor(&metadata));
+        let span_context = context.span().span_context().clone();
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+        assert_eq!(
+            span_context.span_id(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap()
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! In-memory telemetry for asserting on what an app emits in tests.
+
+use crate::middleware::tracing::TracedRequestsFilter;
+use opentelemetry::global::BoxedTracer;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::logs::LoggerProvider;
+use opentelemetry_sdk::metrics::data::ResourceMetrics;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::testing::logs::in_memory_exporter::LogDataWithResource;
+use opentelemetry_sdk::testing::logs::InMemoryLogsExporter;
+use opentelemetry_sdk::testing::metrics::InMemoryMetricsExporter;
+use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+use opentelemetry_sdk::trace::TracerProvider;
+use tracing::subscriber::DefaultGuard;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Tracer, logger and meter providers that export into memory.
+///
+/// Install it with [TestTelemetry::set_default], pass
+/// [TestTelemetry::meter_provider] to the app, then read everything that was
+/// emitted with [TestTelemetry::collect].
+#[derive(Debug)]
+pub struct TestTelemetry {
+    spans: InMemorySpanExporter,
+    logs: InMemoryLogsExporter,
+    metrics: InMemoryMetricsExporter,
+    tracer_provider: TracerProvider,
+    logger_provider: LoggerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+/// Everything exported up to a [TestTelemetry::collect] call.
+#[derive(Debug)]
+pub struct Collected {
+    pub spans: Vec<SpanData>,
+    pub logs: Vec<LogDataWithResource>,
+    pub metrics: Vec<ResourceMetrics>,
+}
+
+impl TestTelemetry {
+    pub fn new() -> Self {
+        let spans = InMemorySpanExporter::default();
+        let logs = InMemoryLogsExporter::default();
+        let metrics = InMemoryMetricsExporter::default();
+        let tracer_provider = TracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let logger_provider = LoggerProvider::builder()
+            .with_simple_exporter(logs.clone())
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(
+                PeriodicReader::builder(
+                    metrics.clone(),
+                    opentelemetry_sdk::runtime::TokioCurrentThread,
+                ) // runtime::Tokio has an issue to hang
+                .build(),
+            )
+            .build();
+        Self {
+            spans,
+            logs,
+            metrics,
+            tracer_provider,
+            logger_provider,
+            meter_provider,
+        }
+    }
+
+    /// The provider to hand to `AppContext::new` and `HttpMetrics`.
+    pub fn meter_provider(&self) -> SdkMeterProvider {
+        self.meter_provider.clone()
+    }
+
+    /// A tracer exporting into memory, to hand to `AppContext::with_tracer`.
+    pub fn tracer(&self) -> BoxedTracer {
+        BoxedTracer::new(Box::new(self.tracer_provider.tracer("test_tracer")))
+    }
+
+    /// Routes spans and events on the current thread into memory until the
+    /// guard is dropped.
+    pub fn set_default(&self) -> DefaultGuard {
+        tracing_subscriber::registry()
+            .with(
+                tracing_opentelemetry::layer()
+                    .with_tracer(self.tracer_provider.tracer("test_tracer"))
+                    .with_filter(TracedRequestsFilter),
+            )
+            .with(OpenTelemetryTracingBridge::new(&self.logger_provider))
+            .set_default()
+    }
+
+    /// Flushes every provider and returns what has been exported so far.
+    pub fn collect(&self) -> Collected {
+        for result in self.tracer_provider.force_flush() {
+            result.expect("failed to flush spans");
+        }
+        for result in self.logger_provider.force_flush() {
+            result.expect("failed to flush logs");
+        }
+        self.meter_provider
+            .force_flush()
+            .expect("failed to flush metrics");
+        Collected {
+            spans: self.spans.get_finished_spans().unwrap(),
+            logs: self.logs.get_emitted_logs().unwrap(),
+            metrics: self.metrics.get_finished_metrics().unwrap(),
+        }
+    }
+}
+
+impl Default for TestTelemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TestTelemetry;
+    use crate::api::route;
+    use crate::middleware::tracing::record_trace;
+    use crate::{AppContext, DurationUnit, RandomConfig};
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App};
+    use opentelemetry::logs::AnyValue;
+
+    #[tokio::test]
+    async fn test_collect_random_log() {
+        let telemetry = TestTelemetry::new();
+        let _guard = telemetry.set_default();
+
+        let context =
+            AppContext::new(telemetry.meter_provider()).with_random_config(RandomConfig {
+                unit: DurationUnit::Milliseconds,
+                ..RandomConfig::default()
+            });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .wrap(from_fn(record_trace))
+                .configure(route),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/random").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        drop(resp);
+
+        let collected = telemetry.collect();
+        assert!(collected.logs.iter().any(|log| matches!(
+            &log.record.body,
+            Some(AnyValue::String(body)) if body.as_str().starts_with("took ")
+        )));
+        assert!(collected
+            .spans
+            .iter()
+            .any(|span| span.name == "GET /random"));
+    }
+}